@@ -1,16 +1,104 @@
-use clap::{Arg, Command};
+use crate::atomic::{self, WriteOptions};
+use crate::commands::{self, Message};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::error::ErrorKind;
+use clap::{Arg, ArgAction, ArgGroup, Command};
+use memmap2::Mmap;
+use pngme::chunk::{Chunk, ChunkRef};
+use pngme::encrypt;
+use pngme::lsb::{self, Channels};
+use pngme::payload::{self, Layer};
+use pngme::png::{Png, PngRef};
+use pngme::recipient::{self, Identity, Recipient};
+use pngme::signature::{self, SigningKey, Verification, VerifyingKey};
+use pngme::text::{
+    CompressedTextChunk, InternationalTextChunk, TextChunk, TextualChunk, MAX_TEXT_LENGTH,
+};
+use pngme::PngError;
+use std::cell::OnceCell;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
-use std::io::{BufRead, BufReader};
-use std::{path::PathBuf, str::FromStr};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 enum Action {
     Encode,
     Decode,
     Remove,
     Print,
+    Validate,
+    Keygen,
+    Sign,
+    Verify,
+    Capacity,
+    Text(TextAction),
+}
+
+// The `text` subcommands, which work on tEXt, zTXt and iTXt chunks by keyword.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TextAction {
+    List,
+    Get(String),
+    Set(TextualChunk),
+    Remove(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Output {
+    File(PathBuf),
+    Stdout,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
+
+// How decode writes out a chunk's data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DecodeFormat {
+    Text,
+    Raw,
+    Hex,
+    Base64,
+}
+
+// Which chunks of the given type decode and remove act on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Which {
+    First,
+    // counting from 0, among chunks of the given type only
+    Index(usize),
+    All,
+}
+
+// Where encode hides the message, and decode looks for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Method {
+    // in a chunk of its own
+    Chunk,
+    // in the least significant bits of the image's pixels
+    Lsb,
+}
+
+// Where the message to encode comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Payload {
+    Inline(Vec<u8>),
+    File(PathBuf),
+    Stdin,
+}
+
+// Kept out of Config's Debug output.
+struct Passphrase(Vec<u8>);
+
+impl std::fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Passphrase(..)")
+    }
 }
 
 #[derive(Debug)]
@@ -18,60 +106,532 @@ pub struct Config {
     action: Action,
     file: PathBuf,
     chunk_type: Option<String>,
-    chunk_data: Option<Vec<u8>>,
+    payload: Option<Payload>,
+    // messages longer than this are split across several chunks
+    split_size: Option<usize>,
+    method: Method,
+    channels: Channels,
+    which: Which,
+    mmap: bool,
+    print_bytes: bool,
+    compress: bool,
+    encrypt: bool,
+    decrypt: bool,
+    // asked for at most once, however many messages need it
+    passphrase: OnceCell<Passphrase>,
+    // key files for --recipient and --identity
+    recipients: Vec<PathBuf>,
+    identities: Vec<PathBuf>,
+    // key files for sign and verify
+    keys: Vec<PathBuf>,
+    signing: bool,
+    // how long a compressed message (for decode) or compressed text (for text
+    // list and get) may inflate to
+    max_length: usize,
+    format: Format,
+    decode_format: DecodeFormat,
+    output: Output,
+    write_options: WriteOptions,
 }
 
 impl Config {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let matches = Command::new("PNGme")
+        let mut command = Command::new("PNGme")
         .version("0.1")
         .author("BeniiAlCo")
         .about("An implementation of PNGme: a way of encoding and decoding messages hidden in PNG files.")
         .arg_required_else_help(true)
         .arg(Arg::new("Action")
             .required(true)
-            .value_parser(["encode", "decode", "remove", "print"])
+            .value_parser(["encode", "decode", "remove", "print", "validate", "keygen", "sign", "verify", "capacity"])
             .value_parser(Self::action_to_enum)
-            .value_name("ACTION")
-            .requires_ifs([("encode", "Type"), ("encode", "Data"), ("decode", "Type"), ("remove", "Type")]))
-        .arg(Arg::new("File")
-            .required(true)
-            .value_parser(clap::value_parser!(PathBuf))
-            .value_name("FILE"))
+            .value_name("ACTION"))
+        .arg(Self::file_arg())
         .arg(Arg::new("Type")
-            .value_parser(Self::type_is_4_bytes)
             .value_name("TYPE"))
         .arg(Arg::new("Data")
             .value_parser(Self::data_to_u8)
             .value_name("DATA")) 
-        .get_matches();
+        .arg(Arg::new("DataFile")
+            .long("data-file")
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("PATH")
+            .help("Encode the contents of PATH instead of DATA"))
+        .arg(Arg::new("DataStdin")
+            .long("data-stdin")
+            .action(ArgAction::SetTrue)
+            .help("Encode whatever is read from stdin instead of DATA"))
+        .group(ArgGroup::new("Payload")
+            .args(["Data", "DataFile", "DataStdin"]))
+        .arg(Arg::new("Compress")
+            .long("compress")
+            .action(ArgAction::SetTrue)
+            .help("Compress the message before hiding it (decode uncompresses it again by itself)"))
+        .arg(Arg::new("Encrypt")
+            .long("encrypt")
+            .action(ArgAction::SetTrue)
+            .help("Encrypt the message with a passphrase, taken from $PNGME_PASSPHRASE or asked for"))
+        .arg(Arg::new("Decrypt")
+            .long("decrypt")
+            .action(ArgAction::SetTrue)
+            .help("Decrypt a message encrypted with --encrypt, taking the passphrase the same way"))
+        .arg(Arg::new("Recipient")
+            .long("recipient")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("PATH")
+            .conflicts_with("Encrypt")
+            .help("Encrypt the message to the public keys in PATH, so only their secret keys can decode it (can be given more than once)"))
+        .arg(Arg::new("Identity")
+            .long("identity")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("PATH")
+            .help("Decrypt a message encrypted with --recipient, using the secret keys in PATH (can be given more than once)"))
+        .arg(Arg::new("Key")
+            .long("key")
+            .action(ArgAction::Append)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("PATH")
+            .help("The signing key to sign with, or the verifying keys to check a signature against (which can be given more than once)"))
+        .arg(Arg::new("Signing")
+            .long("signing")
+            .action(ArgAction::SetTrue)
+            .help("Make a key pair for sign and verify, rather than for --recipient and --identity"))
+        .arg(Arg::new("SplitSize")
+            .long("split-size")
+            .value_parser(clap::value_parser!(usize))
+            .value_name("BYTES")
+            .help("Split a message longer than BYTES across as many chunks of type TYPE as it takes, none of them longer than BYTES (decode puts it back together)"))
+        .arg(Arg::new("Method")
+            .long("method")
+            .value_parser(["chunk", "lsb"])
+            .default_value("chunk")
+            .value_name("METHOD")
+            .help("Encode or decode the message in a chunk of type TYPE, or (lsb) in the least significant bits of the image's pixels, taking no TYPE"))
+        .arg(Arg::new("Channels")
+            .long("channels")
+            .value_parser(|s: &str| Channels::from_str(s).map_err(|err| err.to_string()))
+            .default_value("rgb")
+            .value_name("CHANNELS")
+            .help("Which channels --method lsb and capacity use, as letters from rgba (decode needs the same ones as encode)"))
+        .arg(Arg::new("All")
+            .long("all")
+            .action(ArgAction::SetTrue)
+            .help("Decode or remove every chunk of type TYPE, not just the first"))
+        .arg(Arg::new("Index")
+            .long("index")
+            .value_parser(clap::value_parser!(usize))
+            .value_name("N")
+            .help("Decode or remove the Nth chunk of type TYPE, counting from 0"))
+        .group(ArgGroup::new("Which")
+            .args(["All", "Index"]))
+        .arg(Arg::new("Mmap")
+            .long("mmap")
+            .action(ArgAction::SetTrue)
+            .help("Memory-map FILE instead of reading it into memory (decode and print only)"))
+        .arg(Arg::new("Output")
+            .short('o')
+            .long("output")
+            .global(true)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("PATH")
+            .help("Write to PATH (or stdout, for -): the changed PNG for encode and remove, instead of back to FILE, or the message for decode"))
+        .arg(Arg::new("Format")
+            .long("format")
+            .global(true)
+            .value_parser(["text", "json"])
+            .default_value("text")
+            .value_name("FORMAT")
+            .help("Print, decode, validate or get and list text as plain text or as JSON (the JSON layout is in the README)"))
+        .arg(Arg::new("Bytes")
+            .long("bytes")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("Format")
+            .help("Print every byte of FILE instead of a list of its chunks"))
+        .arg(Arg::new("Raw")
+            .long("raw")
+            .action(ArgAction::SetTrue)
+            .help("Decode the message as the exact bytes stored, without taking off any layers encode wrapped it in"))
+        .arg(Arg::new("MaxLength")
+            .long("max-length")
+            .value_parser(clap::value_parser!(usize))
+            .value_name("BYTES")
+            .help("Decode a compressed message that inflates to up to BYTES, rather than the usual 16 MiB"))
+        .arg(Arg::new("Hex")
+            .long("hex")
+            .action(ArgAction::SetTrue)
+            .help("Decode the message as hexadecimal"))
+        .arg(Arg::new("Base64")
+            .long("base64")
+            .action(ArgAction::SetTrue)
+            .help("Decode the message as base64"))
+        .group(ArgGroup::new("DecodeFormat")
+            .args(["Raw", "Hex", "Base64"]))
+        .group(ArgGroup::new("JsonFormat")
+            .arg("Format")
+            .conflicts_with("DecodeFormat"))
+        .arg(Arg::new("Preserve")
+            .long("preserve")
+            .global(true)
+            .action(ArgAction::SetTrue)
+            .help("Keep FILE's access and modification times when changing it"))
+        .arg(Arg::new("Backup")
+            .long("backup")
+            .global(true)
+            .action(ArgAction::SetTrue)
+            .help("Copy FILE to FILE.bak before changing it"))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(Command::new("text")
+            .about("Read and write the standard tEXt, zTXt and iTXt text chunks, by keyword")
+            .subcommand_required(true)
+            .subcommand(Command::new("list")
+                .about("List every text chunk")
+                .arg(Self::file_arg())
+                .arg(Self::max_length_arg()))
+            .subcommand(Command::new("get")
+                .about("Print the text stored under KEYWORD")
+                .arg(Self::file_arg())
+                .arg(Self::keyword_arg())
+                .arg(Self::max_length_arg()))
+            .subcommand(Command::new("set")
+                .about("Store TEXT under KEYWORD, replacing whatever was there")
+                .arg(Self::file_arg())
+                .arg(Self::keyword_arg())
+                .arg(Arg::new("Text")
+                    .required(true)
+                    .value_name("TEXT"))
+                .arg(Arg::new("Compress")
+                    .long("compress")
+                    .action(ArgAction::SetTrue)
+                    .help("Compress the text (as zTXt, or as compressed iTXt)"))
+                .arg(Arg::new("Language")
+                    .long("language")
+                    .value_name("TAG")
+                    .help("Store the text as iTXt, tagged as being in language TAG (such as en-GB)"))
+                .arg(Arg::new("Translated")
+                    .long("translated")
+                    .value_name("KEYWORD")
+                    .help("Store the text as iTXt, with KEYWORD translated into the text's language")))
+            .subcommand(Command::new("remove")
+                .about("Remove every text chunk with KEYWORD")
+                .arg(Self::file_arg())
+                .arg(Self::keyword_arg())));
+        let matches = command.get_matches_mut();
+
+        if let Some(("text", text)) = matches.subcommand() {
+            return Self::text_config(text);
+        }
 
         let action = matches.get_one::<Action>("Action").cloned().unwrap();
         let file = matches.get_one::<PathBuf>("File").cloned().unwrap();
-
-        let (chunk_type, chunk_data) = match action {
-            Action::Encode => (
-                (matches.get_one::<Option<String>>("Type").cloned().unwrap()),
-                matches.get_one::<Option<Vec<u8>>>("Data").cloned().unwrap(),
-            ),
-            Action::Decode | Action::Remove => (
-                matches.get_one::<Option<String>>("Type").cloned().unwrap(),
-                None,
-            ),
-            Action::Print => (None, None),
+        let which = if matches.get_flag("All") {
+            Which::All
+        } else if let Some(index) = matches.get_one::<usize>("Index") {
+            Which::Index(*index)
+        } else {
+            Which::First
+        };
+        let split_size = matches.get_one::<usize>("SplitSize").copied();
+        let method = match matches.get_one::<String>("Method").map(String::as_str) {
+            Some("lsb") if matches!(action, Action::Encode | Action::Decode | Action::Capacity) => {
+                Method::Lsb
+            }
+            Some("lsb") => command
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    "--method lsb can only be used with encode, decode and capacity",
+                )
+                .exit(),
+            _ => Method::Chunk,
         };
+        let channels = matches.get_one::<Channels>("Channels").copied().unwrap();
+        if method == Method::Lsb {
+            // the pixels only ever hold the one message
+            for (id, flag) in [
+                ("SplitSize", "--split-size"),
+                ("All", "--all"),
+                ("Index", "--index"),
+            ] {
+                if matches.value_source(id) == Some(clap::parser::ValueSource::CommandLine) {
+                    command
+                        .error(
+                            ErrorKind::ArgumentConflict,
+                            format!("{flag} can't be used with --method lsb"),
+                        )
+                        .exit();
+                }
+            }
+        }
+        let mmap = matches.get_flag("Mmap");
+        let print_bytes = matches.get_flag("Bytes");
+        let compress = matches.get_flag("Compress");
+        let encrypt = matches.get_flag("Encrypt");
+        let decrypt = matches.get_flag("Decrypt");
+        let paths = |id: &str| -> Vec<PathBuf> {
+            matches
+                .get_many::<PathBuf>(id)
+                .map(|paths| paths.cloned().collect())
+                .unwrap_or_default()
+        };
+        let recipients = paths("Recipient");
+        let identities = paths("Identity");
+        let keys = paths("Key");
+        // checked here rather than by clap, which would otherwise show --key
+        // as required in every usage line
+        if matches!(action, Action::Sign | Action::Verify) && keys.is_empty() {
+            command
+                .error(
+                    ErrorKind::MissingRequiredArgument,
+                    "sign and verify need a key: --key PATH",
+                )
+                .exit();
+        }
+        let signing = matches.get_flag("Signing");
+        let format = Self::format(&matches);
+        let decode_format = if matches.get_flag("Raw") {
+            DecodeFormat::Raw
+        } else if matches.get_flag("Hex") {
+            DecodeFormat::Hex
+        } else if matches.get_flag("Base64") {
+            DecodeFormat::Base64
+        } else {
+            DecodeFormat::Text
+        };
+        let output = Self::output(&matches, &file, action == Action::Decode);
+        let write_options = Self::write_options(&matches);
+
+        let (chunk_type, payload) = Self::positionals(&matches, &action, method)
+            .unwrap_or_else(|(kind, message)| command.error(kind, message).exit());
 
         Ok(Config {
             action,
             file,
             chunk_type,
-            chunk_data,
+            payload,
+            split_size,
+            method,
+            channels,
+            which,
+            mmap,
+            print_bytes,
+            compress,
+            encrypt,
+            decrypt,
+            passphrase: OnceCell::new(),
+            recipients,
+            identities,
+            keys,
+            signing,
+            max_length: matches
+                .get_one::<usize>("MaxLength")
+                .copied()
+                .unwrap_or(payload::MAX_MESSAGE_LENGTH),
+            format,
+            decode_format,
+            output,
+            write_options,
         })
     }
 
-    fn type_is_4_bytes(s: &str) -> Result<Option<String>, String> {
+    fn text_config(matches: &clap::ArgMatches) -> Result<Self, Box<dyn Error>> {
+        let (name, matches) = matches.subcommand().unwrap();
+        let file = matches.get_one::<PathBuf>("File").cloned().unwrap();
+        let keyword = || matches.get_one::<String>("Keyword").cloned().unwrap();
+        let action = match name {
+            "list" => TextAction::List,
+            "get" => TextAction::Get(keyword()),
+            "set" => TextAction::Set(Self::text_chunk(matches)?),
+            _ => TextAction::Remove(keyword()),
+        };
+        let output = Self::output(
+            matches,
+            &file,
+            matches!(action, TextAction::List | TextAction::Get(_)),
+        );
+
+        Ok(Config {
+            action: Action::Text(action),
+            file,
+            chunk_type: None,
+            payload: None,
+            split_size: None,
+            method: Method::Chunk,
+            channels: Channels::default(),
+            which: Which::First,
+            mmap: false,
+            print_bytes: false,
+            compress: false,
+            encrypt: false,
+            decrypt: false,
+            passphrase: OnceCell::new(),
+            recipients: Vec::new(),
+            identities: Vec::new(),
+            keys: Vec::new(),
+            signing: false,
+            max_length: matches
+                .try_get_one::<usize>("MaxLength")
+                .ok()
+                .flatten()
+                .copied()
+                .unwrap_or(MAX_TEXT_LENGTH),
+            format: Self::format(matches),
+            decode_format: DecodeFormat::Text,
+            output,
+            write_options: Self::write_options(matches),
+        })
+    }
+
+    // Picks the simplest kind of text chunk that can hold what was asked for:
+    // iTXt is only used for a language, a translated keyword, or text that
+    // Latin-1 can't hold.
+    fn text_chunk(matches: &clap::ArgMatches) -> Result<TextualChunk, PngError> {
+        let keyword = matches.get_one::<String>("Keyword").unwrap();
+        let text = matches.get_one::<String>("Text").unwrap();
+        let compress = matches.get_flag("Compress");
+        let language = matches.get_one::<String>("Language");
+        let translated = matches.get_one::<String>("Translated");
+
+        let latin1 = if language.is_some() || translated.is_some() {
+            None
+        } else if compress {
+            Some(CompressedTextChunk::new(keyword, text).map(TextualChunk::Compressed))
+        } else {
+            Some(TextChunk::new(keyword, text).map(TextualChunk::Text))
+        };
+        match latin1 {
+            Some(Err(PngError::InvalidTextField { field: "text", .. })) | None => {
+                InternationalTextChunk::new(
+                    keyword,
+                    language.map_or("", String::as_str),
+                    translated.map_or("", String::as_str),
+                    text,
+                    compress,
+                )
+                .map(TextualChunk::International)
+            }
+            Some(result) => result,
+        }
+    }
+
+    fn file_arg() -> Arg {
+        Arg::new("File")
+            .required(true)
+            .value_parser(clap::value_parser!(PathBuf))
+            .value_name("FILE")
+            .help(
+                "The PNG to work on, or - to read it from stdin (and write any changes to stdout)",
+            )
+    }
+
+    fn max_length_arg() -> Arg {
+        Arg::new("MaxLength")
+            .long("max-length")
+            .value_parser(clap::value_parser!(usize))
+            .value_name("BYTES")
+            .help("Read compressed text that inflates to up to BYTES, rather than the usual 16 MiB")
+    }
+
+    fn keyword_arg() -> Arg {
+        Arg::new("Keyword").required(true).value_name("KEYWORD")
+    }
+
+    fn format(matches: &clap::ArgMatches) -> Format {
+        match matches.get_one::<String>("Format").map(String::as_str) {
+            Some("json") => Format::Json,
+            _ => Format::Text,
+        }
+    }
+
+    // Actions that only read `file` write to stdout unless told otherwise,
+    // while the ones that change it write back to it.
+    fn output(matches: &clap::ArgMatches, file: &Path, read_only: bool) -> Output {
+        match matches.get_one::<PathBuf>("Output") {
+            Some(path) if Self::is_stdio(path) => Output::Stdout,
+            Some(path) => Output::File(path.clone()),
+            None if read_only || Self::is_stdio(file) => Output::Stdout,
+            None => Output::File(file.to_path_buf()),
+        }
+    }
+
+    fn write_options(matches: &clap::ArgMatches) -> WriteOptions {
+        WriteOptions {
+            preserve_times: matches.get_flag("Preserve"),
+            backup: matches.get_flag("Backup"),
+        }
+    }
+
+    // TYPE and DATA are both positional, so with --method lsb, which has no
+    // chunk type, whatever is in TYPE's place is the message itself.
+    fn positionals(
+        matches: &clap::ArgMatches,
+        action: &Action,
+        method: Method,
+    ) -> Result<(Option<String>, Option<Payload>), (ErrorKind, String)> {
+        let chunk_type = matches.get_one::<String>("Type").cloned();
+        let has_payload = matches.contains_id("Data")
+            || matches.contains_id("DataFile")
+            || matches.get_flag("DataStdin");
+        let no_payload = || {
+            (
+                ErrorKind::MissingRequiredArgument,
+                "encode needs a message: DATA, --data-file or --data-stdin".to_string(),
+            )
+        };
+
+        match (action, method) {
+            (Action::Encode, Method::Lsb) => match chunk_type {
+                Some(_) if has_payload => Err((
+                    ErrorKind::ArgumentConflict,
+                    "--method lsb hides the message in the pixels, so it takes DATA without a TYPE"
+                        .to_string(),
+                )),
+                Some(data) => Ok((None, Some(Payload::Inline(data.into_bytes())))),
+                None if has_payload => Ok((None, Some(Self::payload(matches)))),
+                None => Err(no_payload()),
+            },
+            (Action::Decode, Method::Lsb) => match chunk_type {
+                Some(_) => Err((
+                    ErrorKind::ArgumentConflict,
+                    "--method lsb reads the message from the pixels, so it takes no TYPE"
+                        .to_string(),
+                )),
+                None => Ok((None, None)),
+            },
+            (Action::Encode | Action::Decode | Action::Remove | Action::Sign, _) => {
+                let chunk_type = match chunk_type {
+                    Some(chunk_type) => Self::type_is_4_bytes(&chunk_type)
+                        .map_err(|err| (ErrorKind::InvalidValue, err))?,
+                    None => {
+                        return Err((
+                            ErrorKind::MissingRequiredArgument,
+                            "TYPE is needed, to say which chunks hold the message".to_string(),
+                        ))
+                    }
+                };
+                let payload = match action {
+                    Action::Encode if has_payload => Some(Self::payload(matches)),
+                    Action::Encode => return Err(no_payload()),
+                    _ => None,
+                };
+                Ok((Some(chunk_type), payload))
+            }
+            (
+                Action::Print
+                | Action::Validate
+                | Action::Keygen
+                | Action::Verify
+                | Action::Capacity
+                | Action::Text(_),
+                _,
+            ) => Ok((None, None)),
+        }
+    }
+
+    fn type_is_4_bytes(s: &str) -> Result<String, String> {
         if s.len() == 4 {
-            Ok(Some(s.to_string()))
+            Ok(s.to_string())
         } else {
             Err("The type provided was not 4 bytes long".to_string())
         }
@@ -85,71 +645,711 @@ impl Config {
         }
     }
 
+    fn payload(matches: &clap::ArgMatches) -> Payload {
+        if let Some(path) = matches.get_one::<PathBuf>("DataFile") {
+            Payload::File(path.clone())
+        } else if matches.get_flag("DataStdin") {
+            Payload::Stdin
+        } else {
+            let data = matches.get_one::<Option<Vec<u8>>>("Data").cloned();
+            Payload::Inline(data.flatten().unwrap_or_default())
+        }
+    }
+
     fn action_to_enum(s: &str) -> Result<Action, String> {
         match s {
             "encode" => Ok(Action::Encode),
             "decode" => Ok(Action::Decode),
             "remove" => Ok(Action::Remove),
             "print" => Ok(Action::Print),
-            _ => Err("Something went wrong!".to_string()),
+            "validate" => Ok(Action::Validate),
+            "keygen" => Ok(Action::Keygen),
+            "sign" => Ok(Action::Sign),
+            "verify" => Ok(Action::Verify),
+            "capacity" => Ok(Action::Capacity),
+            _ => Err(format!(
+                "{s:?} isn't an action: use encode, decode, remove, print, validate, keygen, sign, verify or capacity"
+            )),
         }
     }
 
     pub fn run(self) -> Result<(), Box<dyn std::error::Error>> {
-        use crate::chunk::Chunk;
-        use crate::chunk_type::ChunkType;
-        use crate::png::Png;
+        use pngme::chunk_type::ChunkType;
+        use pngme::stream::ChunkReader;
 
-        let mut buffer = Vec::new();
-        if self.action != Action::Encode {
-            match Self::open(&self.file) {
-                Err(err) => {
-                    eprintln!("Failed to open {}: {err}", self.file.display());
-                    panic!("file was missing")
-                }
-                Ok(mut file) => {
-                    file.read_to_end(&mut buffer)?;
-                }
-            }
+        // FILE is where the new key goes, so there's nothing to open
+        if self.action == Action::Keygen {
+            return self.keygen();
+        }
+
+        // stdin can't be mapped, so it's always read the usual way
+        if self.mmap
+            && !Self::is_stdio(&self.file)
+            && self.method == Method::Chunk
+            && matches!(self.action, Action::Decode | Action::Print)
+        {
+            return self.run_mapped();
         }
 
+        let mut input = Self::open(&self.file)
+            .map_err(|err| format!("Failed to open {}: {err}", self.file.display()))?;
+
         match self.action {
             Action::Encode => {
-                let mut buf = File::create(&self.file).unwrap();
-                let chunk_type = ChunkType::from_str(&self.chunk_type.unwrap()).unwrap();
-                let chunk_data = self.chunk_data.unwrap();
-                buf.write_all(
-                    &Png::from_chunks(vec![Chunk::new(chunk_type, chunk_data)]).as_bytes(),
-                )?;
-                Ok(())
+                let mut chunk_data = payload::escape(self.read_payload()?);
+                if self.compress {
+                    chunk_data = payload::compress(&chunk_data);
+                }
+                if self.encrypt {
+                    chunk_data =
+                        encrypt::encrypt_with_passphrase(&chunk_data, self.passphrase(true)?)?;
+                }
+                if !self.recipients.is_empty() {
+                    let recipients = Self::read_keys::<Recipient>(&self.recipients)?;
+                    chunk_data = recipient::encrypt_to_recipients(&chunk_data, &recipients)?;
+                }
+                let mut png = Self::read_png(&mut input)?;
+                if self.method == Method::Lsb {
+                    lsb::embed(&mut png, &chunk_data, self.channels)?;
+                    return self.write_png(&png);
+                }
+                let chunk_type = ChunkType::from_str(self.chunk_type.as_ref().unwrap())?;
+                match self.split_size {
+                    Some(size) if chunk_data.len() > size => {
+                        for piece in payload::split(&chunk_data, size)? {
+                            png.insert_chunk(Chunk::try_new(chunk_type, piece)?);
+                        }
+                    }
+                    _ => png.insert_chunk(Chunk::try_new(chunk_type, chunk_data)?),
+                }
+                self.write_png(&png)
+            }
+            Action::Decode if self.method == Method::Lsb => {
+                let png = Self::read_png(&mut input)?;
+                let data = lsb::extract(&png, self.channels)?;
+                let (data, layers) =
+                    self.unwrap_layers(data, &[], || "The message in the pixels".to_string())?;
+                let message = Message {
+                    chunk: None,
+                    pieces: 0,
+                    data,
+                    layers,
+                };
+                self.write_output(|output| self.write_message(&message, output))
             }
             Action::Decode => {
-                let chunk_type = &self.chunk_type.unwrap();
-                let png: Png = TryFrom::try_from(buffer.as_ref()).unwrap();
-                if let Some(chunk_data) = png.chunk_by_type(chunk_type) {
-                    println!("{}", Chunk::data_as_string(chunk_data).unwrap());
-                } else {
-                    eprintln!("Something went wrong! The chunk type provided does not exist in the PNG file provided!")
-                }
-                Ok(())
+                let chunk_type = self.chunk_type.as_ref().unwrap();
+                let mut chunks = ChunkReader::new(input)?;
+                // Only reads as far into the file as it has to: once the chunk
+                // being asked for turns up, the rest is never looked at.
+                let matching = std::iter::from_fn(|| loop {
+                    let offset = chunks.offset();
+                    match chunks.next()? {
+                        Ok(chunk) if &chunk.chunk_type().to_string() != chunk_type => continue,
+                        chunk => return Some(chunk.map(|chunk| (offset, chunk))),
+                    }
+                });
+                let found = self
+                    .select(Self::messages(matching, |(_, chunk)| {
+                        Self::piece_id(chunk.data())
+                    }))
+                    .into_iter()
+                    .collect::<Result<Vec<_>, _>>()?;
+                let found: Vec<Vec<ChunkRef>> = found
+                    .iter()
+                    .map(|message| {
+                        message
+                            .iter()
+                            .map(|(offset, chunk)| chunk.as_chunk_ref(*offset))
+                            .collect()
+                    })
+                    .collect();
+                self.write_messages(&found)
             }
             Action::Remove => {
-                let mut buf = File::create(&self.file).unwrap();
-                let chunk_type = &self.chunk_type.unwrap();
-                let mut png: Png = TryFrom::try_from(buffer.as_ref()).unwrap();
-                png.remove_chunk(chunk_type).ok();
-                buf.write_all(&png.as_bytes())?;
+                let chunk_type = self.chunk_type.as_ref().unwrap();
+                let mut png = Self::read_png(&mut input)?;
+                // all the pieces of a split message go together
+                let chunks = png.chunks();
+                let indices = png.chunk_indices(chunk_type).into_iter().map(Ok);
+                let found = self
+                    .select(Self::messages(indices, |&index| {
+                        Self::piece_id(chunks[index].data())
+                    }))
+                    .into_iter()
+                    .collect::<Result<Vec<Vec<usize>>, PngError>>()?;
+                // as with decode, and without rewriting the file
+                if found.is_empty() {
+                    return Err(self.nothing_found());
+                }
+                for index in found.into_iter().flatten().rev() {
+                    png.remove_chunk_at(index)?;
+                }
+                self.write_png(&png)
+            }
+            Action::Print if self.print_bytes => {
+                let png = Self::read_png(&mut input)?;
+                println!("{}", png);
                 Ok(())
             }
             Action::Print => {
-                let png: Png = TryFrom::try_from(buffer.as_ref()).unwrap();
+                let mut buffer = Vec::new();
+                input.read_to_end(&mut buffer)?;
+                self.print_chunks(&PngRef::try_from(buffer.as_slice())?)
+            }
+            Action::Validate => {
+                let mut buffer = Vec::new();
+                input.read_to_end(&mut buffer)?;
+                // bad CRCs are reported along with everything else, rather
+                // than stopping the check at the first one
+                let (png, mut problems) = Png::parse_lenient(&buffer)?;
+                problems.extend(png.validate());
+                self.report_problems(&problems)
+            }
+            Action::Sign => {
+                let key = match Self::read_keys::<SigningKey>(&self.keys)?.as_slice() {
+                    [key] => key.clone(),
+                    _ => return Err("sign takes a single signing key".into()),
+                };
+                let mut buffer = Vec::new();
+                input.read_to_end(&mut buffer)?;
+                let png = PngRef::try_from(buffer.as_slice())?;
+                let signature = signature::sign(&png, self.chunk_type.as_ref().unwrap(), &key)?;
+                // a PNG only ever holds the one signature
+                let mut png = png.to_png()?;
+                png.remove_chunks(signature::CHUNK_TYPE);
+                png.insert_chunk(signature);
+                self.write_png(&png)
+            }
+            Action::Verify => {
+                let keys = Self::read_keys::<VerifyingKey>(&self.keys)?;
+                let mut buffer = Vec::new();
+                input.read_to_end(&mut buffer)?;
+                let png = PngRef::try_from(buffer.as_slice())?;
+                self.report_verification(&signature::verify(&png, &keys)?)
+            }
+            Action::Capacity => {
+                let png = Self::read_png(&mut input)?;
+                let capacity = lsb::capacity(&png, self.channels)?;
+                match self.format {
+                    Format::Text => println!(
+                        "{} bytes can be hidden in the pixels, using channels {}",
+                        capacity, self.channels
+                    ),
+                    Format::Json => println!(
+                        "{}",
+                        commands::capacity_json(capacity, &self.channels.to_string())
+                    ),
+                }
+                Ok(())
+            }
+            Action::Text(ref text) => self.run_text(text, &mut input),
+            Action::Keygen => unreachable!("keygen doesn't read FILE"),
+        }
+    }
+
+    // Decode and print only ever look at the file, so they can work straight
+    // off a memory map of it without copying anything into a buffer.
+    fn run_mapped(self) -> Result<(), Box<dyn Error>> {
+        let file = File::open(&self.file)?;
+        // Safety: the map is only read from, and only for as long as this
+        // function runs. If another process truncates the file in that time
+        // we may be killed by SIGBUS, which is the usual caveat with mmap.
+        let map = unsafe { Mmap::map(&file)? };
+        let png = PngRef::try_from(&map[..])?;
+
+        match self.action {
+            Action::Decode => {
+                let chunk_type = self.chunk_type.as_ref().unwrap();
+                // As when streaming, every chunk up to the last one needed has
+                // its CRC checked, whatever its type.
+                let matching = png
+                    .chunks()
+                    .map(|chunk| chunk.check_crc().map(|()| chunk))
+                    .filter(|chunk| match chunk {
+                        Ok(chunk) => &chunk.chunk_type().to_string() == chunk_type,
+                        Err(_) => true,
+                    });
+                let found = self
+                    .select(Self::messages(matching, |chunk| {
+                        Self::piece_id(chunk.data())
+                    }))
+                    .into_iter()
+                    .collect::<Result<Vec<_>, PngError>>()?;
+                self.write_messages(&found)
+            }
+            Action::Print if self.print_bytes => {
+                for chunk in png.chunks() {
+                    chunk.check_crc()?;
+                }
                 println!("{}", png);
                 Ok(())
             }
+            Action::Print => self.print_chunks(&png),
+            Action::Encode
+            | Action::Remove
+            | Action::Validate
+            | Action::Keygen
+            | Action::Sign
+            | Action::Verify
+            | Action::Capacity
+            | Action::Text(_) => {
+                unreachable!("only decode and print are memory-mapped")
+            }
+        }
+    }
+
+    // The passphrase for --encrypt and --decrypt comes from $PNGME_PASSPHRASE if
+    // it's set, so scripts can use it, or else is asked for on the terminal
+    // (twice when encrypting, to catch typos).
+    fn passphrase(&self, confirm: bool) -> Result<&[u8], Box<dyn Error>> {
+        if let Some(Passphrase(passphrase)) = self.passphrase.get() {
+            return Ok(passphrase);
+        }
+        let passphrase = match std::env::var("PNGME_PASSPHRASE") {
+            Ok(passphrase) => passphrase,
+            Err(_) => {
+                let passphrase = rpassword::prompt_password("Passphrase: ")?;
+                if confirm && rpassword::prompt_password("Passphrase again: ")? != passphrase {
+                    return Err("The passphrases didn't match".into());
+                }
+                passphrase
+            }
+        };
+        if passphrase.is_empty() {
+            return Err("The passphrase can't be empty".into());
+        }
+        Ok(&self
+            .passphrase
+            .get_or_init(|| Passphrase(passphrase.into_bytes()))
+            .0)
+    }
+
+    // Makes a new key pair, writing the secret key to FILE, readable only by
+    // its owner, and the public key to FILE.pub for handing out. An existing
+    // FILE is never overwritten, since that would lose the key in it for good.
+    fn keygen(&self) -> Result<(), Box<dyn Error>> {
+        let (secret, public) = if self.signing {
+            let key = SigningKey::generate();
+            (key.to_secret_string(), key.verifying_key().to_string())
+        } else {
+            let identity = Identity::generate();
+            (
+                identity.to_secret_string(),
+                identity.recipient().to_string(),
+            )
+        };
+        let contents = format!("# created by pngme keygen\n# public key: {public}\n{secret}\n");
+        if Self::is_stdio(&self.file) {
+            print!("{contents}");
+            return Ok(());
+        }
+
+        // Neither file is ever overwritten: the public one is made first, and
+        // taken away again if the secret one can't be, so nothing is left
+        // half done.
+        let mut public_path = self.file.clone().into_os_string();
+        public_path.push(".pub");
+        let public_path = PathBuf::from(public_path);
+        let create = |path: &Path, mode: u32| {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(mode);
+            }
+            #[cfg(not(unix))]
+            let _ = mode;
+            options
+                .open(path)
+                .map_err(|err| format!("Can't create {}: {err}", path.display()))
+        };
+        let mut public_file = create(&public_path, 0o666)?;
+        let mut file = match create(&self.file, 0o600) {
+            Ok(file) => file,
+            Err(err) => {
+                drop(public_file);
+                std::fs::remove_file(&public_path)?;
+                return Err(err.into());
+            }
+        };
+        file.write_all(contents.as_bytes())?;
+        public_file.write_all(format!("{public}\n").as_bytes())?;
+        println!("{public}");
+        Ok(())
+    }
+
+    fn read_keys<T: FromStr<Err = PngError>>(paths: &[PathBuf]) -> Result<Vec<T>, Box<dyn Error>> {
+        let mut keys = Vec::new();
+        for path in paths {
+            let text = std::fs::read_to_string(path)
+                .map_err(|err| format!("Can't read the key file {}: {err}", path.display()))?;
+            let found = recipient::parse_keys::<T>(&text)
+                .map_err(|err| format!("{} in {}", err, path.display()))?;
+            if found.is_empty() {
+                return Err(format!("There are no keys in {}", path.display()).into());
+            }
+            keys.extend(found);
+        }
+        Ok(keys)
+    }
+
+    fn read_payload(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let reader: Box<dyn Read> = match self.payload.as_ref().unwrap() {
+            Payload::Inline(data) => return Ok(data.clone()),
+            Payload::File(path) => Box::new(File::open(path)?),
+            Payload::Stdin if Self::is_stdio(&self.file) => {
+                return Err("--data-stdin can't be used when FILE is also read from stdin".into())
+            }
+            Payload::Stdin => Box::new(std::io::stdin().lock()),
+        };
+
+        // Reading one byte past the limit is enough to tell that the payload is
+        // too big, without reading the whole thing into memory first.
+        let mut data = Vec::new();
+        reader
+            .take(Chunk::MAX_LENGTH as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() > Chunk::MAX_LENGTH as usize {
+            return Err(format!(
+                "The message is too big to fit in a chunk (the limit is {} bytes)",
+                Chunk::MAX_LENGTH
+            )
+            .into());
         }
+        Ok(data)
+    }
+
+    fn print_chunks(&self, png: &PngRef) -> Result<(), Box<dyn Error>> {
+        let mut stdout = std::io::stdout().lock();
+        match self.format {
+            Format::Text => commands::print_chunks(png, &mut stdout)?,
+            Format::Json => commands::print_json(png, &mut stdout)?,
+        }
+        Ok(())
+    }
+
+    fn run_text(
+        &self,
+        action: &TextAction,
+        input: &mut impl BufRead,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut png = Self::read_png(input)?;
+        let missing =
+            |keyword: &str| format!("There is no text chunk with the keyword {keyword:?}");
+
+        match action {
+            TextAction::List => {
+                let mut texts = Vec::new();
+                for text in png.text_chunks_with_max_length(self.max_length) {
+                    match text {
+                        Ok(text) => texts.push(text),
+                        Err(err) => eprintln!("Skipping a text chunk: {err}"),
+                    }
+                }
+                self.write_output(|output| match self.format {
+                    Format::Text => Ok(commands::print_texts(&texts, output)?),
+                    Format::Json => {
+                        let texts: Vec<_> = texts.iter().map(commands::text_json).collect();
+                        Ok(writeln!(output, "{}", serde_json::Value::Array(texts))?)
+                    }
+                })
+            }
+            TextAction::Get(keyword) => {
+                let text = png
+                    .text_chunks_with_max_length(self.max_length)
+                    .into_iter()
+                    .flatten()
+                    .find(|text| text.keyword() == keyword)
+                    .ok_or_else(|| missing(keyword))?;
+                self.write_output(|output| match self.format {
+                    Format::Text => Ok(writeln!(output, "{}", text.text())?),
+                    Format::Json => Ok(writeln!(output, "{}", commands::text_json(&text))?),
+                })
+            }
+            TextAction::Set(text) => {
+                png.set_text(text.clone());
+                self.write_png(&png)
+            }
+            TextAction::Remove(keyword) => {
+                if png.remove_text(keyword).is_empty() {
+                    return Err(missing(keyword).into());
+                }
+                self.write_png(&png)
+            }
+        }
+    }
+
+    fn report_problems(&self, problems: &[PngError]) -> Result<(), Box<dyn Error>> {
+        let mut stdout = std::io::stdout().lock();
+        match self.format {
+            Format::Text => commands::print_problems(problems, &mut stdout)?,
+            Format::Json => commands::problems_json(problems, &mut stdout)?,
+        }
+        match problems.len() {
+            0 => Ok(()),
+            1 => Err("Found 1 problem with the PNG".into()),
+            n => Err(format!("Found {n} problems with the PNG").into()),
+        }
+    }
+
+    fn report_verification(&self, verification: &Verification) -> Result<(), Box<dyn Error>> {
+        let mut stdout = std::io::stdout().lock();
+        match self.format {
+            Format::Text => commands::print_verification(verification, &mut stdout)?,
+            Format::Json => commands::verification_json(verification, &mut stdout)?,
+        }
+        match verification.problems.len() {
+            0 => Ok(()),
+            1 => Err("Found 1 change since the PNG was signed".into()),
+            n => Err(format!("Found {n} changes since the PNG was signed").into()),
+        }
+    }
+
+    // Narrows the chunks of the asked-for type down to the ones asked for.
+    fn select<T>(&self, matching: impl Iterator<Item = T>) -> Vec<T> {
+        match self.which {
+            Which::First => matching.take(1).collect(),
+            Which::Index(n) => matching.skip(n).take(1).collect(),
+            Which::All => matching.collect(),
+        }
+    }
+
+    // A message is usually one chunk, but a split one is all of its pieces,
+    // which encode puts one after the other. Gathering them back up means
+    // --index and --all count messages rather than chunks. A message is every
+    // piece in a row with the same id; `payload::join` then checks that they
+    // add up to a whole one.
+    fn messages<T, E>(
+        chunks: impl Iterator<Item = Result<T, E>>,
+        piece_id: impl Fn(&T) -> Option<u64>,
+    ) -> impl Iterator<Item = Result<Vec<T>, E>> {
+        let mut chunks = chunks.peekable();
+        std::iter::from_fn(move || {
+            let first = match chunks.next()? {
+                Ok(first) => first,
+                Err(err) => return Some(Err(err)),
+            };
+            let id = piece_id(&first);
+            let mut message = vec![first];
+            if id.is_some() {
+                while let Some(Ok(piece)) =
+                    chunks.next_if(|next| matches!(next, Ok(next) if piece_id(next) == id))
+                {
+                    message.push(piece);
+                }
+            }
+            Some(Ok(message))
+        })
+    }
+
+    // The id of the split message `data` is a piece of, or None if it isn't a
+    // piece.
+    fn piece_id(data: &[u8]) -> Option<u64> {
+        payload::piece(data).ok().map(|piece| piece.id)
+    }
+
+    // The error for when no chunk of the type matches the selection.
+    fn nothing_found(&self) -> Box<dyn Error> {
+        let chunk_type = self.chunk_type.as_ref().unwrap();
+        match self.which {
+            Which::Index(n) => {
+                format!("There is no {chunk_type} chunk at index {n} in the PNG file provided")
+                    .into()
+            }
+            _ => PngError::ChunkNotFound {
+                chunk_type: chunk_type.clone(),
+            }
+            .into(),
+        }
+    }
+
+    fn write_messages(&self, chunks: &[Vec<ChunkRef>]) -> Result<(), Box<dyn Error>> {
+        if chunks.is_empty() {
+            return Err(self.nothing_found());
+        }
+
+        let messages = chunks
+            .iter()
+            .map(|message| self.open_message(message))
+            .collect::<Result<Vec<_>, _>>()?;
+        let write = |output: &mut dyn Write| -> Result<(), Box<dyn Error>> {
+            // with --all, JSON output is a list even if only one chunk matched
+            if self.format == Format::Json && self.which == Which::All {
+                let messages: Vec<_> = messages.iter().map(commands::message_json).collect();
+                writeln!(output, "{}", serde_json::Value::Array(messages))?;
+                return Ok(());
+            }
+            for message in &messages {
+                self.write_message(message, output)?;
+            }
+            Ok(())
+        };
+
+        self.write_output(write)
+    }
+
+    // Writes something other than a PNG (a message, or text) to wherever the
+    // output is meant to go.
+    fn write_output(
+        &self,
+        write: impl FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        match &self.output {
+            Output::Stdout => {
+                let mut stdout = BufWriter::new(std::io::stdout().lock());
+                write(&mut stdout)?;
+                Ok(stdout.flush()?)
+            }
+            Output::File(path) => atomic::write_file(path, None, self.write_options, write),
+        }
+    }
+
+    // Takes off whatever layers encode wrapped the message in, outermost first,
+    // starting by putting it back together if it was split across `chunks`.
+    fn open_message<'a>(&self, chunks: &[ChunkRef<'a>]) -> Result<Message<'a>, Box<dyn Error>> {
+        let chunk = chunks[0];
+        let (data, layers) = self.unwrap_layers(chunk.data().to_vec(), chunks, || {
+            format!("The {} chunk's message", chunk.chunk_type())
+        })?;
+        Ok(Message {
+            chunk: Some(chunk),
+            pieces: chunks.len(),
+            data,
+            layers,
+        })
+    }
+
+    // `name` says which message it is, for errors: a message that isn't in a
+    // chunk has no `chunks`, and so can't have been split.
+    fn unwrap_layers(
+        &self,
+        mut data: Vec<u8>,
+        chunks: &[ChunkRef],
+        name: impl Fn() -> String,
+    ) -> Result<(Vec<u8>, Vec<Layer>), Box<dyn Error>> {
+        // --raw is the bytes exactly as they are stored, pieces and all
+        if self.decode_format == DecodeFormat::Raw {
+            if !chunks.is_empty() {
+                data = chunks
+                    .iter()
+                    .flat_map(|chunk| chunk.data())
+                    .copied()
+                    .collect();
+            }
+            return Ok((data, Vec::new()));
+        }
+        let mut layers = Vec::new();
+        while let Some(layer) = payload::layer(&data)? {
+            if layers
+                .last()
+                .is_some_and(|outer: &Layer| !outer.can_contain(layer))
+            {
+                return Err(PngError::InvalidPayload {
+                    reason: "its layers aren't the ones encode would have wrapped it in",
+                }
+                .into());
+            }
+            data = match layer {
+                Layer::Split if layers.is_empty() && !chunks.is_empty() => {
+                    payload::join(chunks.iter().map(|chunk| chunk.data()))?
+                }
+                Layer::Split => {
+                    return Err(PngError::InvalidPayload {
+                        reason: "it has a piece of another message inside it",
+                    }
+                    .into())
+                }
+                Layer::Compressed => payload::decompress(&data, self.max_length)?,
+                Layer::Escaped => payload::body(&data, layer)?.to_vec(),
+                Layer::Passphrase if !self.decrypt => {
+                    return Err(format!("{} is encrypted; use --decrypt to read it", name()).into())
+                }
+                Layer::Passphrase => {
+                    encrypt::decrypt_with_passphrase(&data, self.passphrase(false)?)?
+                }
+                Layer::Recipients if self.identities.is_empty() => {
+                    return Err(format!(
+                        "{} is encrypted to public keys; use --identity to read it",
+                        name()
+                    )
+                    .into())
+                }
+                Layer::Recipients => recipient::decrypt_with_identities(
+                    &data,
+                    &Self::read_keys::<Identity>(&self.identities)?,
+                )?,
+            };
+            layers.push(layer);
+            // whatever is inside is the message, however it starts
+            if layer == Layer::Escaped {
+                break;
+            }
+        }
+        Ok((data, layers))
+    }
+
+    fn write_message(
+        &self,
+        message: &Message,
+        output: &mut dyn Write,
+    ) -> Result<(), Box<dyn Error>> {
+        let data = message.data.as_slice();
+        match self.decode_format {
+            _ if self.format == Format::Json => {
+                writeln!(output, "{}", commands::message_json(message))?;
+            }
+            DecodeFormat::Text => {
+                let text = std::str::from_utf8(data).map_err(|err| {
+                    let holder = match message.chunk {
+                        Some(chunk) => format!("The {} chunk", chunk.chunk_type()),
+                        None => "The message in the pixels".to_string(),
+                    };
+                    format!("{holder} doesn't hold text ({err}); use --raw, --hex or --base64 to get at it")
+                })?;
+                writeln!(output, "{}", text)?;
+            }
+            DecodeFormat::Raw => output.write_all(data)?,
+            DecodeFormat::Hex => {
+                for byte in data {
+                    write!(output, "{:02x}", byte)?;
+                }
+                writeln!(output)?;
+            }
+            DecodeFormat::Base64 => writeln!(output, "{}", BASE64_STANDARD.encode(data))?,
+        }
+        Ok(())
+    }
+
+    fn write_png(&self, png: &Png) -> Result<(), Box<dyn Error>> {
+        match &self.output {
+            Output::Stdout => Ok(png.write_to(BufWriter::new(std::io::stdout().lock()))?),
+            Output::File(path) => {
+                let source = (!Self::is_stdio(&self.file)).then_some(self.file.as_path());
+                atomic::write_file(path, source, self.write_options, |output| {
+                    Ok(png.write_to(output)?)
+                })
+            }
+        }
+    }
+
+    fn read_png(input: &mut impl BufRead) -> Result<Png, Box<dyn Error>> {
+        let mut buffer = Vec::new();
+        input.read_to_end(&mut buffer)?;
+        Ok(TryFrom::try_from(buffer.as_ref())?)
     }
 
     fn open(file: &PathBuf) -> Result<Box<dyn BufRead>, Box<dyn Error>> {
-        Ok(Box::new(BufReader::new(File::open(file)?)))
+        if Self::is_stdio(file) {
+            Ok(Box::new(std::io::stdin().lock()))
+        } else {
+            Ok(Box::new(BufReader::new(File::open(file)?)))
+        }
+    }
+
+    // `-` stands for stdin when reading and stdout when writing.
+    fn is_stdio(path: &Path) -> bool {
+        path.as_os_str() == "-"
     }
 }
@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::fs::{self, File, FileTimes};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WriteOptions {
+    // keep the original file's access and modification times
+    pub preserve_times: bool,
+    // copy the original file to `<file>.bak` before replacing it
+    pub backup: bool,
+}
+
+// Writes whatever `write` produces to `path`, without ever leaving a
+// half-written file behind. The new contents go to a temporary file next to
+// `path`, which is synced and then renamed into place, so a failure at any
+// point leaves whatever was at `path` exactly as it was.
+//
+// If `path` is a symlink, the file it points to is the one replaced, and the
+// link is left alone.
+//
+// `source` is the file the new contents were made from (often `path` itself).
+// Its owner and permissions are carried over (the owner only as far as we are
+// allowed to), as they would be if it had been truncated and rewritten in
+// place, and its times are with `preserve_times`.
+// Without a source (when reading from stdin, say) whatever is already at
+// `path` stands in for it, if there is anything.
+pub fn write_file(
+    path: &Path,
+    source: Option<&Path>,
+    options: WriteOptions,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let original = match source {
+        Some(source) => Some(fs::metadata(source)?),
+        None => fs::metadata(path).ok(),
+    };
+    let path = &fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut temp = match original {
+        Some(_) => NamedTempFile::new_in(dir)?,
+        None => new_file_in(dir)?,
+    };
+    {
+        let mut writer = BufWriter::new(temp.as_file_mut());
+        write(&mut writer)?;
+        writer.flush()?;
+    }
+
+    let file = temp.as_file();
+    if let Some(original) = original {
+        set_owner(file, &original);
+        file.set_permissions(original.permissions())?;
+        if options.preserve_times {
+            file.set_times(
+                FileTimes::new()
+                    .set_accessed(original.accessed()?)
+                    .set_modified(original.modified()?),
+            )?;
+        }
+    }
+    file.sync_all()?;
+
+    if options.backup && path.exists() {
+        fs::copy(path, backup_path(path))?;
+    }
+
+    temp.persist(path)?;
+    sync_dir(dir)?;
+    Ok(())
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+// Temporary files are only readable by their owner, which is too strict for
+// a brand new output file, so it gets the mode any other new file would: 0o666
+// less the umask.
+#[cfg(unix)]
+fn new_file_in(dir: &Path) -> std::io::Result<NamedTempFile> {
+    use std::os::unix::fs::PermissionsExt;
+    tempfile::Builder::new()
+        .permissions(fs::Permissions::from_mode(0o666))
+        .tempfile_in(dir)
+}
+
+#[cfg(not(unix))]
+fn new_file_in(dir: &Path) -> std::io::Result<NamedTempFile> {
+    NamedTempFile::new_in(dir)
+}
+
+// Only root can give a file away, so for anyone else this fails unless the
+// owner is already us, and the file is left belonging to whoever wrote it.
+#[cfg(unix)]
+fn set_owner(file: &File, original: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    let _ = std::os::unix::fs::fchown(file, Some(original.uid()), Some(original.gid()));
+}
+
+#[cfg(not(unix))]
+fn set_owner(_file: &File, _original: &fs::Metadata) {}
+
+// The rename only survives a crash once the directory entry itself has been
+// written out.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_write_file_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, b"old").unwrap();
+
+        write_file(&path, Some(&path), WriteOptions::default(), |w| {
+            Ok(w.write_all(b"new")?)
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!backup_path(&path).exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_file_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        let output = dir.path().join("output.png");
+        fs::write(&path, b"old").unwrap();
+
+        let options = WriteOptions {
+            preserve_times: false,
+            backup: true,
+        };
+        write_file(&output, Some(&path), options, |w| Ok(w.write_all(b"new")?)).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read(&output).unwrap(), b"new");
+        assert!(!backup_path(&output).exists());
+    }
+
+    #[test]
+    fn test_write_file_without_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("output.png");
+
+        write_file(&output, None, WriteOptions::default(), |w| {
+            Ok(w.write_all(b"new")?)
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(&output).unwrap(), b"new");
+        // the same as any other new file, whatever the umask is
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let plain = dir.path().join("plain");
+            File::create(&plain).unwrap();
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&output), mode(&plain));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("image.png");
+        let link = dir.path().join("link.png");
+        fs::write(&target, b"old").unwrap();
+        std::os::unix::fs::symlink("image.png", &link).unwrap();
+
+        write_file(&link, Some(&link), WriteOptions::default(), |w| {
+            Ok(w.write_all(b"new")?)
+        })
+        .unwrap();
+
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_failed_write_leaves_original() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, b"old").unwrap();
+
+        let result = write_file(&path, Some(&path), WriteOptions::default(), |w| {
+            w.write_all(b"half")?;
+            Err("something went wrong".into())
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"old");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_backup_and_preserve_times() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.png");
+        fs::write(&path, b"old").unwrap();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        let options = WriteOptions {
+            preserve_times: true,
+            backup: true,
+        };
+        write_file(&path, Some(&path), options, |w| Ok(w.write_all(b"new")?)).unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(fs::read(backup_path(&path)).unwrap(), b"old");
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), modified);
+    }
+}
@@ -1,6 +1,9 @@
 use super::chunk_type::ChunkType;
+use crate::error::PngError;
 use std::fmt::Display;
 
+pub(crate) static CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
 #[derive(Clone, PartialEq, Eq)]
 pub struct Chunk {
     length: u32,
@@ -10,30 +13,18 @@ pub struct Chunk {
 }
 
 impl TryFrom<&[u8]> for Chunk {
-    type Error = &'static str;
+    type Error = PngError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if value.len() >= 12 {
-            let length = u32::from_be_bytes(value[..4].try_into().unwrap());
-            let chunk_type =
-                ChunkType::try_from(TryInto::<[u8; 4]>::try_into(&value[4..8]).unwrap()).unwrap();
-            let chunk_data = value[8..value.len() - 4].to_vec();
-            let crc = u32::from_be_bytes(value[value.len() - 4..].try_into().unwrap());
-
-            let chunk = Chunk {
-                length,
-                chunk_type,
-                chunk_data: chunk_data.clone(),
-                crc,
-            };
-
-            if chunk == Chunk::new(chunk_type, chunk_data) {
-                Ok(chunk)
-            } else {
-                Err("The checksum for the provided value is invalid.")
-            }
+        let chunk = ChunkRef::read(value, 0)?;
+        chunk.check_crc()?;
+        if chunk.size() < value.len() {
+            Err(PngError::TrailingBytes {
+                offset: chunk.size(),
+                count: value.len() - chunk.size(),
+            })
         } else {
-            Err("Provided value not long enough to contain a chunk.")
+            Ok(chunk.to_chunk())
         }
     }
 }
@@ -45,23 +36,63 @@ impl Display for Chunk {
 }
 
 impl Chunk {
+    // The PNG spec caps chunk lengths at 2^31 - 1 so they fit in a signed int.
+    pub const MAX_LENGTH: u32 = (1 << 31) - 1;
+
+    // Panics if `data` is longer than MAX_LENGTH; use `try_new` when the data
+    // comes from somewhere that isn't already known to be small enough.
     pub fn new(chunk_type: ChunkType, data: Vec<u8>) -> Chunk {
-        let crc = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(
-            &chunk_type
-                .bytes()
-                .iter()
-                .chain(data.iter())
-                .copied()
-                .collect::<Vec<u8>>(),
-        );
+        Self::try_new(chunk_type, data).expect("length bigger than the PNG limit")
+    }
+
+    pub fn try_new(chunk_type: ChunkType, data: Vec<u8>) -> Result<Chunk, PngError> {
+        if data.len() > Self::MAX_LENGTH as usize {
+            return Err(PngError::LengthOverflow {
+                offset: 0,
+                length: data.len(),
+            });
+        }
+        let crc = Self::compute_crc(&chunk_type, &data);
+        Ok(Chunk {
+            length: data.len() as u32,
+            chunk_type,
+            chunk_data: data,
+            crc,
+        })
+    }
+
+    // Builds a chunk around a CRC that was read from somewhere, rather than
+    // working it out. `data` must already have been checked against MAX_LENGTH.
+    pub(crate) fn from_parts(chunk_type: ChunkType, data: Vec<u8>, crc: u32) -> Chunk {
         Chunk {
-            length: data.len().try_into().expect("length bigger than u32"),
+            length: data.len() as u32,
             chunk_type,
             chunk_data: data,
             crc,
         }
     }
 
+    // `offset` is where the chunk's CRC field sits, for the error message.
+    pub(crate) fn check_crc(&self, offset: usize) -> Result<(), PngError> {
+        if self.is_crc_valid() {
+            Ok(())
+        } else {
+            Err(PngError::CrcMismatch {
+                offset,
+                chunk_type: self.chunk_type.bytes(),
+                expected: Self::compute_crc(&self.chunk_type, &self.chunk_data),
+                actual: self.crc,
+            })
+        }
+    }
+
+    pub(crate) fn compute_crc(chunk_type: &ChunkType, data: &[u8]) -> u32 {
+        let mut digest = CRC.digest();
+        digest.update(&chunk_type.bytes());
+        digest.update(data);
+        digest.finalize()
+    }
+
     pub fn length(&self) -> u32 {
         self.length
     }
@@ -78,7 +109,21 @@ impl Chunk {
         self.crc
     }
 
-    pub fn data_as_string(&self) -> Result<String, Box<dyn std::error::Error>> {
+    pub fn is_crc_valid(&self) -> bool {
+        self.crc == Self::compute_crc(&self.chunk_type, &self.chunk_data)
+    }
+
+    // A borrowed view of this chunk, as if it had been read from `offset`.
+    pub fn as_chunk_ref(&self, offset: usize) -> ChunkRef<'_> {
+        ChunkRef {
+            chunk_type: self.chunk_type,
+            data: &self.chunk_data,
+            crc: self.crc,
+            offset,
+        }
+    }
+
+    pub fn data_as_string(&self) -> Result<String, PngError> {
         Ok(std::str::from_utf8(self.data())?.to_string())
     }
 
@@ -94,6 +139,115 @@ impl Chunk {
     }
 }
 
+// A chunk that borrows its data from the buffer it was read out of, so that
+// walking through a file doesn't copy anything.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef<'a> {
+    chunk_type: ChunkType,
+    data: &'a [u8],
+    crc: u32,
+    offset: usize,
+}
+
+impl<'a> ChunkRef<'a> {
+    // Reads the chunk starting at `input[offset..]`. The stored CRC is kept as
+    // it was found, so callers have to decide for themselves whether to check
+    // it. Errors point at their position in the whole of `input`.
+    pub fn read(input: &'a [u8], offset: usize) -> Result<ChunkRef<'a>, PngError> {
+        let value = input.get(offset..).unwrap_or_default();
+        let truncated = |needed| PngError::TruncatedChunk {
+            offset,
+            needed,
+            available: value.len(),
+        };
+        if value.len() < 12 {
+            return Err(truncated(12));
+        }
+
+        let length = u32::from_be_bytes([value[0], value[1], value[2], value[3]]);
+        if length > Chunk::MAX_LENGTH {
+            return Err(PngError::LengthOverflow {
+                offset,
+                length: length as usize,
+            });
+        }
+
+        let chunk_type = ChunkType::try_from([value[4], value[5], value[6], value[7]])
+            .map_err(|err| err.offset_by(offset + 4))?;
+
+        let total = 12 + length as usize;
+        if value.len() < total {
+            return Err(truncated(total));
+        }
+
+        let crc = &value[total - 4..total];
+        Ok(ChunkRef {
+            chunk_type,
+            data: &value[8..total - 4],
+            crc: u32::from_be_bytes([crc[0], crc[1], crc[2], crc[3]]),
+            offset,
+        })
+    }
+
+    pub fn length(&self) -> u32 {
+        self.data.len() as u32
+    }
+
+    pub fn chunk_type(&self) -> &ChunkType {
+        &self.chunk_type
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    // Where the chunk's length field starts in the input it was read from.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // The number of bytes the chunk takes up, including its length, type and
+    // CRC fields.
+    pub fn size(&self) -> usize {
+        12 + self.data.len()
+    }
+
+    pub fn is_crc_valid(&self) -> bool {
+        self.crc == Chunk::compute_crc(&self.chunk_type, self.data)
+    }
+
+    pub fn data_as_str(&self) -> Result<&'a str, PngError> {
+        Ok(std::str::from_utf8(self.data)?)
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        Chunk::from_parts(self.chunk_type, self.data.to_vec(), self.crc)
+    }
+
+    pub fn check_crc(&self) -> Result<(), PngError> {
+        if self.is_crc_valid() {
+            Ok(())
+        } else {
+            Err(PngError::CrcMismatch {
+                offset: self.offset + self.size() - 4,
+                chunk_type: self.chunk_type.bytes(),
+                expected: Chunk::compute_crc(&self.chunk_type, self.data),
+                actual: self.crc,
+            })
+        }
+    }
+}
+
+impl Display for ChunkRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.chunk_type)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -129,6 +283,14 @@ mod tests {
         assert_eq!(chunk.crc(), 2882656334);
     }
 
+    #[test]
+    fn test_try_new_chunk() {
+        let chunk_type = ChunkType::from_str("RuSt").unwrap();
+        let chunk = Chunk::try_new(chunk_type, vec![0, 159, 255]).unwrap();
+        assert_eq!(chunk.data(), &[0, 159, 255]);
+        assert!(chunk.is_crc_valid());
+    }
+
     #[test]
     fn test_chunk_length() {
         let chunk = testing_chunk();
@@ -200,7 +362,111 @@ mod tests {
 
         let chunk = Chunk::try_from(chunk_data.as_ref());
 
-        assert!(chunk.is_err());
+        assert_eq!(
+            chunk.err(),
+            Some(PngError::CrcMismatch {
+                offset: 50,
+                chunk_type: *b"RuSt",
+                expected: 2882656334,
+                actual: 2882656333,
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_chunk_from_bytes() {
+        let chunk = Chunk::try_from([0, 0, 0, 0, 82, 117].as_ref());
+
+        assert_eq!(
+            chunk.err(),
+            Some(PngError::TruncatedChunk {
+                offset: 0,
+                needed: 12,
+                available: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_length_past_end_of_input() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data.truncate(30);
+
+        let chunk = Chunk::try_from(chunk_data.as_ref());
+
+        assert_eq!(
+            chunk.err(),
+            Some(PngError::TruncatedChunk {
+                offset: 0,
+                needed: 54,
+                available: 30,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_length_overflow() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data[..4].copy_from_slice(&u32::MAX.to_be_bytes());
+
+        let chunk = Chunk::try_from(chunk_data.as_ref());
+
+        assert_eq!(
+            chunk.err(),
+            Some(PngError::LengthOverflow {
+                offset: 0,
+                length: u32::MAX as usize,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_trailing_bytes() {
+        let mut chunk_data = testing_chunk().as_bytes();
+        chunk_data.extend_from_slice(&[1, 2, 3]);
+
+        let chunk = Chunk::try_from(chunk_data.as_ref());
+
+        assert_eq!(
+            chunk.err(),
+            Some(PngError::TrailingBytes {
+                offset: 54,
+                count: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunk_ref() {
+        let mut input = vec![0, 0];
+        input.extend(testing_chunk().as_bytes());
+
+        let chunk = ChunkRef::read(&input, 2).unwrap();
+
+        assert_eq!(chunk.offset(), 2);
+        assert_eq!(chunk.size(), 54);
+        assert_eq!(chunk.chunk_type().to_string(), String::from("RuSt"));
+        assert_eq!(
+            chunk.data_as_str().unwrap(),
+            "This is where your secret message will be!"
+        );
+        assert!(chunk.is_crc_valid());
+        assert!(chunk.to_chunk() == testing_chunk());
+    }
+
+    #[test]
+    fn test_chunk_ref_truncated() {
+        let input = testing_chunk().as_bytes();
+
+        assert_eq!(
+            ChunkRef::read(&input[..40], 0).err(),
+            Some(PngError::TruncatedChunk {
+                offset: 0,
+                needed: 54,
+                available: 40,
+            })
+        );
+        assert!(ChunkRef::read(&input, 100).is_err());
     }
 
     #[test]
@@ -3,6 +3,7 @@
 // - check the validity of the entire chunk type
 // - check the special meaning of capitalization for each of the four bytes
 
+use crate::error::PngError;
 use std::fmt::Display;
 use std::str::FromStr;
 
@@ -16,11 +17,16 @@ pub struct ChunkType {
 }
 
 impl TryFrom<[u8; 4]> for ChunkType {
-    type Error = &'static str;
+    type Error = PngError;
 
     fn try_from(value: [u8; 4]) -> Result<Self, Self::Error> {
         let byte_5 = 0b00100000;
-        if value.iter().all(|v| v.is_ascii_alphabetic()) {
+        if let Some(offset) = value.iter().position(|v| !v.is_ascii_alphabetic()) {
+            Err(PngError::InvalidChunkTypeByte {
+                offset,
+                byte: value[offset],
+            })
+        } else {
             Ok(ChunkType {
                 bytes: value,
                 ancillary: value[0] & byte_5 == byte_5,
@@ -28,20 +34,18 @@ impl TryFrom<[u8; 4]> for ChunkType {
                 reserved: value[2] & byte_5 == byte_5,
                 safe_to_copy: value[3] & byte_5 == byte_5,
             })
-        } else {
-            Err("Values not in correct range (Some values in this chunk are not ASCII alphabetic)")
         }
     }
 }
 
 impl FromStr for ChunkType {
-    type Err = &'static str;
+    type Err = PngError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if let Ok(s) = std::convert::TryInto::<[u8; 4]>::try_into(s.bytes().collect::<Vec<u8>>()) {
-            Ok(ChunkType::try_from(s)?)
+            ChunkType::try_from(s)
         } else {
-            Err("Input value not correct (input string not 4 bytes long)")
+            Err(PngError::InvalidChunkTypeLength { length: s.len() })
         }
     }
 }
@@ -163,6 +167,21 @@ mod tests {
         assert!(chunk.is_err());
     }
 
+    #[test]
+    pub fn test_invalid_chunk_type_errors() {
+        assert_eq!(
+            ChunkType::from_str("Ru1t"),
+            Err(PngError::InvalidChunkTypeByte {
+                offset: 2,
+                byte: b'1'
+            })
+        );
+        assert_eq!(
+            ChunkType::from_str("RuStY"),
+            Err(PngError::InvalidChunkTypeLength { length: 5 })
+        );
+    }
+
     #[test]
     pub fn test_chunk_type_string() {
         let chunk = ChunkType::from_str("RuSt").unwrap();
@@ -0,0 +1,513 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use pngme::chunk::ChunkRef;
+use pngme::payload::Layer;
+use pngme::png::PngRef;
+use pngme::signature::Verification;
+use pngme::{Ihdr, PngError, TextualChunk};
+use serde_json::{json, Value};
+use std::io::Write;
+
+// How many bytes of each chunk's data `print` shows.
+const PREVIEW_LEN: usize = 16;
+
+// Lists the chunks in `png`, one per line. CRCs are checked and marked rather
+// than treated as errors, since a listing is most useful when something's
+// wrong with the file.
+pub fn print_chunks(png: &PngRef, output: &mut impl Write) -> std::io::Result<()> {
+    if let Ok(ihdr) = png.ihdr() {
+        writeln!(output, "Image: {}\n", ihdr)?;
+    }
+    writeln!(
+        output,
+        "{:>3}  {:>10}  {:4}  {:>10}  {:14}  {:9}  {:7}  {:6}  DATA",
+        "#", "OFFSET", "TYPE", "LENGTH", "CRC", "KIND", "SCOPE", "COPY"
+    )?;
+    for (index, chunk) in png.chunks().enumerate() {
+        print_chunk(index, &chunk, output)?;
+    }
+    Ok(())
+}
+
+fn print_chunk(index: usize, chunk: &ChunkRef, output: &mut impl Write) -> std::io::Result<()> {
+    let chunk_type = chunk.chunk_type();
+    let crc_mark = if chunk.is_crc_valid() { "ok" } else { "BAD" };
+    let kind = if chunk_type.is_critical() {
+        "critical"
+    } else {
+        "ancillary"
+    };
+    let scope = if chunk_type.is_public() {
+        "public"
+    } else {
+        "private"
+    };
+    let copy = if chunk_type.is_safe_to_copy() {
+        "safe"
+    } else {
+        "unsafe"
+    };
+
+    writeln!(
+        output,
+        "{:>3}  {:>10}  {:4}  {:>10}  {:#010x} {:3}  {:9}  {:7}  {:6}  {}",
+        index,
+        chunk.offset(),
+        chunk_type,
+        chunk.length(),
+        chunk.crc(),
+        crc_mark,
+        kind,
+        scope,
+        copy,
+        preview(chunk.data())
+    )
+}
+
+fn preview(data: &[u8]) -> String {
+    let shown = &data[..data.len().min(PREVIEW_LEN)];
+    let mut preview = format!("\"{}\"", shown.escape_ascii());
+    if data.len() > PREVIEW_LEN {
+        preview.push_str("...");
+    }
+    preview
+}
+
+// The JSON form of `print`. The layout is described in the README.
+pub fn print_json(png: &PngRef, output: &mut impl Write) -> std::io::Result<()> {
+    let chunks: Vec<Value> = png
+        .chunks()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut value = chunk_json(&chunk);
+            value["index"] = json!(index);
+            value
+        })
+        .collect();
+    let value = json!({
+        "signature": hex(png.header()),
+        "chunks": chunks,
+    });
+    writeln!(output, "{}", value)
+}
+
+// A message found by decode: the chunk it was in (the first, if it was split
+// across several, and none if it was in the pixels), and what the data turned
+// out to be once any layers pngme wrapped it in were taken off.
+pub struct Message<'a> {
+    pub chunk: Option<ChunkRef<'a>>,
+    pub pieces: usize,
+    pub data: Vec<u8>,
+    pub layers: Vec<Layer>,
+}
+
+// The JSON form of `decode`: the same description of the chunk that `print`
+// gives, plus the message (and the message as text, when it is valid UTF-8).
+// A message from the pixels has no chunk to describe.
+pub fn message_json(message: &Message) -> Value {
+    let mut value = message.chunk.as_ref().map_or(json!({}), chunk_json);
+    let layers: Vec<String> = message.layers.iter().map(Layer::to_string).collect();
+    value["layers"] = json!(layers);
+    value["pieces"] = json!(message.pieces);
+    value["message"] = json!(BASE64_STANDARD.encode(&message.data));
+    value["text"] = json!(std::str::from_utf8(&message.data).ok());
+    value
+}
+
+// The JSON form of `capacity`.
+pub fn capacity_json(capacity: usize, channels: &str) -> Value {
+    json!({
+        "capacity": capacity,
+        "channels": channels,
+    })
+}
+
+fn chunk_json(chunk: &ChunkRef) -> Value {
+    let chunk_type = chunk.chunk_type();
+    json!({
+        "type": chunk_type.to_string(),
+        "offset": chunk.offset(),
+        "length": chunk.length(),
+        "crc": chunk.crc(),
+        "crc_valid": chunk.is_crc_valid(),
+        "critical": chunk_type.is_critical(),
+        "public": chunk_type.is_public(),
+        "reserved_bit_valid": chunk_type.is_reserved_bit_valid(),
+        "safe_to_copy": chunk_type.is_safe_to_copy(),
+        "fields": known_fields(chunk),
+        "data": BASE64_STANDARD.encode(chunk.data()),
+    })
+}
+
+// Picks apart the standard chunks whose layout is simple enough to show as
+// fields. Anything else, or anything the wrong size, is left as null.
+fn known_fields(chunk: &ChunkRef) -> Value {
+    let data = chunk.data();
+    let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+
+    match (&chunk.chunk_type().bytes(), data.len()) {
+        (b"IHDR", _) => match Ihdr::try_from(*chunk) {
+            Ok(ihdr) => json!({
+                "width": ihdr.width(),
+                "height": ihdr.height(),
+                "bit_depth": ihdr.bit_depth(),
+                "colour_type": ihdr.colour_type() as u8,
+                "compression_method": ihdr.compression_method(),
+                "filter_method": ihdr.filter_method(),
+                "interlace_method": ihdr.interlace_method(),
+            }),
+            Err(_) => Value::Null,
+        },
+        (b"gAMA", 4) => json!({ "gamma": u32_at(0) as f64 / 100000.0 }),
+        (b"sRGB", 1) => json!({ "rendering_intent": data[0] }),
+        (b"pHYs", 9) => json!({
+            "pixels_per_unit_x": u32_at(0),
+            "pixels_per_unit_y": u32_at(4),
+            "unit": if data[8] == 1 { "metre" } else { "unknown" },
+        }),
+        (b"tIME", 7) => json!({
+            "year": u16::from_be_bytes([data[0], data[1]]),
+            "month": data[2],
+            "day": data[3],
+            "hour": data[4],
+            "minute": data[5],
+            "second": data[6],
+        }),
+        (b"tEXt" | b"zTXt" | b"iTXt", _) => match TextualChunk::try_from(*chunk) {
+            Ok(text) => text_json(&text),
+            Err(_) => Value::Null,
+        },
+        _ => Value::Null,
+    }
+}
+
+// Lists what `validate` found wrong with a PNG, one problem per line.
+pub fn print_problems(problems: &[PngError], output: &mut impl Write) -> std::io::Result<()> {
+    if problems.is_empty() {
+        return writeln!(output, "No problems found");
+    }
+    for problem in problems {
+        writeln!(output, "{}", problem)?;
+    }
+    Ok(())
+}
+
+pub fn problems_json(problems: &[PngError], output: &mut impl Write) -> std::io::Result<()> {
+    let value = json!({
+        "valid": problems.is_empty(),
+        "problems": problems.iter().map(problem_json).collect::<Vec<_>>(),
+    });
+    writeln!(output, "{}", value)
+}
+
+fn problem_json(problem: &PngError) -> Value {
+    json!({
+        "offset": problem.offset(),
+        "message": problem.to_string(),
+    })
+}
+
+pub fn print_verification(
+    verification: &Verification,
+    output: &mut impl Write,
+) -> std::io::Result<()> {
+    writeln!(output, "Signed by {}", verification.signer)?;
+    if verification.problems.is_empty() {
+        return writeln!(output, "Nothing has changed since signing");
+    }
+    for problem in &verification.problems {
+        writeln!(output, "{}", problem)?;
+    }
+    Ok(())
+}
+
+pub fn verification_json(
+    verification: &Verification,
+    output: &mut impl Write,
+) -> std::io::Result<()> {
+    let value = json!({
+        "valid": verification.problems.is_empty(),
+        "signer": verification.signer.to_string(),
+        "problems": verification.problems.iter().map(problem_json).collect::<Vec<_>>(),
+    });
+    writeln!(output, "{}", value)
+}
+
+// Lists text chunks as `keyword [type]: text`, with the language in the
+// brackets too for iTXt chunks that have one.
+pub fn print_texts(
+    texts: &[TextualChunk],
+    output: &mut (impl Write + ?Sized),
+) -> std::io::Result<()> {
+    for text in texts {
+        let chunk_type = text.chunk_type();
+        match text {
+            TextualChunk::International(itxt) if !itxt.language_tag().is_empty() => writeln!(
+                output,
+                "{} [{}, {}]: {}",
+                text.keyword(),
+                chunk_type,
+                itxt.language_tag(),
+                text.text()
+            )?,
+            _ => writeln!(
+                output,
+                "{} [{}]: {}",
+                text.keyword(),
+                chunk_type,
+                text.text()
+            )?,
+        }
+    }
+    Ok(())
+}
+
+pub fn text_json(text: &TextualChunk) -> Value {
+    let mut value = json!({
+        "keyword": text.keyword(),
+        "text": text.text(),
+    });
+    match text {
+        TextualChunk::Text(_) => value["compressed"] = json!(false),
+        TextualChunk::Compressed(_) => value["compressed"] = json!(true),
+        TextualChunk::International(itxt) => {
+            value["compressed"] = json!(itxt.is_compressed());
+            value["language_tag"] = json!(itxt.language_tag());
+            value["translated_keyword"] = json!(itxt.translated_keyword());
+        }
+    }
+    value
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pngme::chunk::Chunk;
+    use pngme::chunk_type::ChunkType;
+    use pngme::png::Png;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_print_chunks() {
+        let png = Png::from_chunks(vec![
+            Chunk::new(ChunkType::from_str("IHDR").unwrap(), vec![0, 0, 0, 50]),
+            Chunk::new(
+                ChunkType::from_str("ruSt").unwrap(),
+                b"This is where your secret message will be!".to_vec(),
+            ),
+        ]);
+        let mut bytes = png.as_bytes();
+        // break the second chunk's CRC
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        let mut output = Vec::new();
+        print_chunks(&PngRef::try_from(bytes.as_slice()).unwrap(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("IHDR"));
+        assert!(lines[1].contains(" ok "));
+        assert!(lines[1].contains("critical   public   unsafe"));
+        assert!(lines[1].ends_with("\"\\x00\\x00\\x002\""));
+        assert!(lines[2].contains("        24  ruSt          42"));
+        assert!(lines[2].contains(" BAD "));
+        assert!(lines[2].contains("ancillary  private  safe"));
+        assert!(lines[2].ends_with("\"This is where yo\"..."));
+    }
+
+    #[test]
+    fn test_print_image_header() {
+        let png = Png::from_chunks(vec![Chunk::new(
+            ChunkType::from_str("IHDR").unwrap(),
+            vec![0, 0, 0, 50, 0, 0, 0, 40, 8, 6, 0, 0, 0],
+        )]);
+        let bytes = png.as_bytes();
+
+        let mut output = Vec::new();
+        print_chunks(&PngRef::try_from(bytes.as_slice()).unwrap(), &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "Image: 50x40, 8-bit truecolour with alpha, not interlaced"
+        );
+        assert_eq!(lines[1], "");
+        assert!(lines[3].contains("IHDR"));
+    }
+
+    #[test]
+    fn test_print_json() {
+        let png = Png::from_chunks(vec![
+            Chunk::new(
+                ChunkType::from_str("IHDR").unwrap(),
+                vec![0, 0, 0, 50, 0, 0, 0, 40, 8, 6, 0, 0, 0],
+            ),
+            Chunk::new(
+                ChunkType::from_str("tEXt").unwrap(),
+                b"Comment\0hello".to_vec(),
+            ),
+        ]);
+        let bytes = png.as_bytes();
+
+        let mut output = Vec::new();
+        print_json(&PngRef::try_from(bytes.as_slice()).unwrap(), &mut output).unwrap();
+        let value: Value = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(value["signature"], "89504e470d0a1a0a");
+        assert_eq!(value["chunks"][0]["index"], 0);
+        assert_eq!(value["chunks"][0]["type"], "IHDR");
+        assert_eq!(value["chunks"][0]["offset"], 8);
+        assert_eq!(value["chunks"][0]["critical"], true);
+        assert_eq!(value["chunks"][0]["crc_valid"], true);
+        assert_eq!(value["chunks"][0]["fields"]["width"], 50);
+        assert_eq!(value["chunks"][0]["fields"]["height"], 40);
+        assert_eq!(value["chunks"][0]["fields"]["colour_type"], 6);
+        assert_eq!(value["chunks"][1]["offset"], 33);
+        assert_eq!(value["chunks"][1]["fields"]["keyword"], "Comment");
+        assert_eq!(value["chunks"][1]["fields"]["text"], "hello");
+        assert_eq!(value["chunks"][1]["data"], "Q29tbWVudABoZWxsbw==");
+    }
+
+    #[test]
+    fn test_problems_json() {
+        let problems = [
+            PngError::MissingIend,
+            PngError::TrailingBytes {
+                offset: 60,
+                count: 4,
+            },
+        ];
+
+        let mut output = Vec::new();
+        problems_json(&problems, &mut output).unwrap();
+        let value: Value = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(value["valid"], false);
+        assert_eq!(value["problems"][0]["offset"], Value::Null);
+        assert_eq!(
+            value["problems"][0]["message"],
+            "The PNG does not end with an IEND chunk"
+        );
+        assert_eq!(value["problems"][1]["offset"], 60);
+
+        let mut output = Vec::new();
+        problems_json(&[], &mut output).unwrap();
+        let value: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(value["valid"], true);
+    }
+
+    #[test]
+    fn test_verification_json() {
+        let signer = pngme::SigningKey::generate().verifying_key();
+        let verification = Verification {
+            signer,
+            problems: vec![PngError::ChunkChanged {
+                offset: 33,
+                chunk_type: *b"IDAT",
+            }],
+        };
+
+        let mut output = Vec::new();
+        verification_json(&verification, &mut output).unwrap();
+        let value: Value = serde_json::from_slice(&output).unwrap();
+
+        assert_eq!(value["valid"], false);
+        assert_eq!(value["signer"], signer.to_string());
+        assert_eq!(value["problems"][0]["offset"], 33);
+        assert_eq!(
+            value["problems"][0]["message"],
+            "The IDAT chunk at byte 33 has been changed since the PNG was signed"
+        );
+    }
+
+    #[test]
+    fn test_print_texts() {
+        use pngme::{InternationalTextChunk, TextChunk};
+
+        let texts = [
+            TextualChunk::Text(TextChunk::new("Title", "Dice").unwrap()),
+            TextualChunk::International(
+                InternationalTextChunk::new("Title", "fr", "Titre", "D\u{e9}s", true).unwrap(),
+            ),
+        ];
+
+        let mut output = Vec::new();
+        print_texts(&texts, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Title [tEXt]: Dice\nTitle [iTXt, fr]: D\u{e9}s\n"
+        );
+        assert_eq!(
+            text_json(&texts[1]),
+            json!({
+                "keyword": "Title",
+                "text": "D\u{e9}s",
+                "compressed": true,
+                "language_tag": "fr",
+                "translated_keyword": "Titre",
+            })
+        );
+    }
+
+    #[test]
+    fn test_message_json() {
+        let chunk = Chunk::new(ChunkType::from_str("ruSt").unwrap(), vec![0, 255]);
+        let message = Message {
+            chunk: Some(chunk.as_chunk_ref(8)),
+            pieces: 1,
+            data: chunk.data().to_vec(),
+            layers: Vec::new(),
+        };
+
+        let value = message_json(&message);
+
+        assert_eq!(value["type"], "ruSt");
+        assert_eq!(value["length"], 2);
+        assert_eq!(value["fields"], Value::Null);
+        assert_eq!(value["layers"], json!([]));
+        assert_eq!(value["text"], Value::Null);
+        assert_eq!(value["data"], "AP8=");
+        assert_eq!(value["message"], "AP8=");
+    }
+
+    #[test]
+    fn test_compressed_message_json() {
+        let data = pngme::payload::compress(b"hello");
+        let chunk = Chunk::new(ChunkType::from_str("ruSt").unwrap(), data);
+        let message = Message {
+            chunk: Some(chunk.as_chunk_ref(8)),
+            pieces: 1,
+            data: b"hello".to_vec(),
+            layers: vec![Layer::Compressed],
+        };
+
+        let value = message_json(&message);
+
+        assert_eq!(value["layers"], json!(["zlib"]));
+        assert_eq!(value["text"], "hello");
+        assert_eq!(value["message"], "aGVsbG8=");
+    }
+
+    #[test]
+    fn test_pixel_message_json() {
+        let message = Message {
+            chunk: None,
+            pieces: 0,
+            data: b"hello".to_vec(),
+            layers: Vec::new(),
+        };
+
+        let value = message_json(&message);
+
+        assert_eq!(value["type"], Value::Null);
+        assert_eq!(value["pieces"], 0);
+        assert_eq!(value["text"], "hello");
+    }
+}
@@ -0,0 +1,265 @@
+use crate::error::PngError;
+use crate::payload::{self, Layer};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+// The body of a passphrase layer (see `payload`) is:
+//
+//   kdf (1)             1 for Argon2id
+//   memory (4)          in KiB, like all the numbers here big-endian
+//   iterations (4)
+//   parallelism (4)
+//   salt length (1)
+//   salt
+//   cipher (1)          1 for ChaCha20-Poly1305
+//   nonce (12)
+//   ciphertext          with its 16 byte tag on the end
+//
+// Everything before the ciphertext, the layer header included, is
+// authenticated along with it, so none of the settings can be changed without
+// decryption failing. New kdfs and ciphers get new numbers, and old ones keep
+// working.
+const KDF_ARGON2ID: u8 = 1;
+pub(crate) const CIPHER_CHACHA20_POLY1305: u8 = 1;
+const SALT_LENGTH: usize = 16;
+pub(crate) const NONCE_LENGTH: usize = 12;
+
+// How much work turning a passphrase into a key takes. The defaults are the
+// OWASP recommendation for Argon2id.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    // Anything above these is refused when decrypting, so a message can't make
+    // whoever opens it spend more than a quarter of a gigabyte of memory, or
+    // more than a few seconds, on the key. They leave plenty of room above the
+    // defaults.
+    pub const MAX_MEMORY_KIB: u32 = 256 * 1024;
+    pub const MAX_ITERATIONS: u32 = 10;
+    pub const MAX_PARALLELISM: u32 = 16;
+
+    fn argon2(&self) -> Result<Argon2<'static>, PngError> {
+        if self.memory_kib > Self::MAX_MEMORY_KIB
+            || self.iterations > Self::MAX_ITERATIONS
+            || self.parallelism > Self::MAX_PARALLELISM
+        {
+            return Err(out_of_range());
+        }
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|_| out_of_range())?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+pub fn encrypt_with_passphrase(message: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, PngError> {
+    encrypt_with_params(message, passphrase, KdfParams::default())
+}
+
+pub fn encrypt_with_params(
+    message: &[u8],
+    passphrase: &[u8],
+    params: KdfParams,
+) -> Result<Vec<u8>, PngError> {
+    let mut salt = [0; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    let mut nonce = [0; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut body = vec![KDF_ARGON2ID];
+    body.extend_from_slice(&params.memory_kib.to_be_bytes());
+    body.extend_from_slice(&params.iterations.to_be_bytes());
+    body.extend_from_slice(&params.parallelism.to_be_bytes());
+    body.push(SALT_LENGTH as u8);
+    body.extend_from_slice(&salt);
+    body.push(CIPHER_CHACHA20_POLY1305);
+    body.extend_from_slice(&nonce);
+    let mut data = payload::wrap(Layer::Passphrase, &body);
+
+    let key = derive_key(passphrase, &salt, params)?;
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: message,
+                aad: &data,
+            },
+        )
+        .map_err(|_| PngError::InvalidPayload {
+            reason: "it is too long to encrypt",
+        })?;
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+pub fn decrypt_with_passphrase(data: &[u8], passphrase: &[u8]) -> Result<Vec<u8>, PngError> {
+    let body = payload::body(data, Layer::Passphrase)?;
+    let truncated = || PngError::InvalidPayload {
+        reason: "its encryption header is cut short",
+    };
+    let u32_at = |i: usize| -> Result<u32, PngError> {
+        let bytes = body.get(i..i + 4).ok_or_else(truncated)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    };
+
+    if body.first() != Some(&KDF_ARGON2ID) {
+        return Err(PngError::InvalidPayload {
+            reason:
+                "it was encrypted with a key derivation function this version of pngme doesn't know",
+        });
+    }
+    let params = KdfParams {
+        memory_kib: u32_at(1)?,
+        iterations: u32_at(5)?,
+        parallelism: u32_at(9)?,
+    };
+    let salt_length = *body.get(13).ok_or_else(truncated)? as usize;
+    let salt = body.get(14..14 + salt_length).ok_or_else(truncated)?;
+    let rest = &body[14 + salt_length..];
+    if rest.first() != Some(&CIPHER_CHACHA20_POLY1305) {
+        return Err(PngError::InvalidPayload {
+            reason: "it was encrypted with a cipher this version of pngme doesn't know",
+        });
+    }
+    let nonce = rest.get(1..1 + NONCE_LENGTH).ok_or_else(truncated)?;
+    let ciphertext = &rest[1 + NONCE_LENGTH..];
+    let aad = &data[..data.len() - ciphertext.len()];
+
+    let key = derive_key(passphrase, salt, params)?;
+    ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| PngError::DecryptionFailed)
+}
+
+fn derive_key(passphrase: &[u8], salt: &[u8], params: KdfParams) -> Result<Key, PngError> {
+    let mut key = Key::default();
+    params
+        .argon2()?
+        .hash_password_into(passphrase, salt, &mut key)
+        .map_err(|_| out_of_range())?;
+    Ok(key)
+}
+
+fn out_of_range() -> PngError {
+    PngError::InvalidPayload {
+        reason: "its key derivation settings are out of range",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The default settings are deliberately slow, which tests don't need.
+    const FAST: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_round_trip() {
+        let sealed = encrypt_with_params(b"meet at dawn", b"hunter2", FAST).unwrap();
+
+        assert_eq!(payload::layer(&sealed), Ok(Some(Layer::Passphrase)));
+        assert!(!sealed.windows(12).any(|w| w == b"meet at dawn"));
+        assert_eq!(
+            decrypt_with_passphrase(&sealed, b"hunter2").unwrap(),
+            b"meet at dawn"
+        );
+    }
+
+    #[test]
+    fn test_salt_and_nonce_are_random() {
+        let first = encrypt_with_params(b"same", b"same", FAST).unwrap();
+        let second = encrypt_with_params(b"same", b"same", FAST).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_wrong_passphrase() {
+        let sealed = encrypt_with_params(b"meet at dawn", b"hunter2", FAST).unwrap();
+        assert_eq!(
+            decrypt_with_passphrase(&sealed, b"hunter3"),
+            Err(PngError::DecryptionFailed)
+        );
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let sealed = encrypt_with_params(b"meet at dawn", b"hunter2", FAST).unwrap();
+        // the last byte of the ciphertext, then the first byte of the salt
+        for index in [sealed.len() - 1, payload::HEADER_LENGTH + 14] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                decrypt_with_passphrase(&tampered, b"hunter2"),
+                Err(PngError::DecryptionFailed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_envelopes() {
+        let sealed = encrypt_with_params(b"meet at dawn", b"hunter2", FAST).unwrap();
+
+        for length in [payload::HEADER_LENGTH, payload::HEADER_LENGTH + 10, 30] {
+            assert!(matches!(
+                decrypt_with_passphrase(&sealed[..length], b"hunter2"),
+                Err(PngError::InvalidPayload { .. })
+            ));
+        }
+
+        let mut greedy = sealed.clone();
+        greedy[payload::HEADER_LENGTH + 1] = 0xff;
+        assert_eq!(
+            decrypt_with_passphrase(&greedy, b"hunter2"),
+            Err(out_of_range())
+        );
+
+        assert!(decrypt_with_passphrase(&payload::compress(b"hi"), b"hunter2").is_err());
+    }
+
+    #[test]
+    fn test_kdf_limits() {
+        let limits = KdfParams {
+            memory_kib: KdfParams::MAX_MEMORY_KIB,
+            iterations: KdfParams::MAX_ITERATIONS,
+            parallelism: KdfParams::MAX_PARALLELISM,
+        };
+        assert!(limits.argon2().is_ok());
+        for params in [
+            KdfParams {
+                memory_kib: KdfParams::MAX_MEMORY_KIB + 1,
+                ..limits
+            },
+            KdfParams {
+                iterations: KdfParams::MAX_ITERATIONS + 1,
+                ..limits
+            },
+        ] {
+            assert_eq!(params.argon2().err(), Some(out_of_range()));
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use std::fmt::Display;
+
+// Offsets are byte positions in whatever was being parsed: for a whole file
+// that's from the start of the signature, for a lone chunk it's from the start
+// of its length field, and for a lone chunk type it's the index into the type.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PngError {
+    BadSignature {
+        found: Vec<u8>,
+    },
+    TruncatedChunk {
+        offset: usize,
+        needed: usize,
+        available: usize,
+    },
+    CrcMismatch {
+        offset: usize,
+        chunk_type: [u8; 4],
+        expected: u32,
+        actual: u32,
+    },
+    InvalidChunkTypeByte {
+        offset: usize,
+        byte: u8,
+    },
+    InvalidChunkTypeLength {
+        length: usize,
+    },
+    LengthOverflow {
+        offset: usize,
+        length: usize,
+    },
+    TrailingBytes {
+        offset: usize,
+        count: usize,
+    },
+    MissingIhdr,
+    MissingIend,
+    DuplicateChunk {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    // `before` is whether the chunk should have come before `other` (rather
+    // than after it).
+    ChunkOutOfOrder {
+        offset: usize,
+        chunk_type: [u8; 4],
+        other: [u8; 4],
+        before: bool,
+    },
+    SplitImageData {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    ChunkNotAllowed {
+        offset: usize,
+        chunk_type: [u8; 4],
+        colour_type: u8,
+    },
+    InvalidChunkLength {
+        offset: usize,
+        chunk_type: [u8; 4],
+        length: usize,
+    },
+    UnknownCriticalChunk {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    ReservedBitSet {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    UnexpectedChunkType {
+        offset: usize,
+        expected: [u8; 4],
+        found: [u8; 4],
+    },
+    InvalidIhdrField {
+        field: &'static str,
+        value: u32,
+    },
+    InvalidBitDepth {
+        bit_depth: u8,
+        colour_type: u8,
+    },
+    InvalidKeyword {
+        keyword: String,
+    },
+    InvalidTextField {
+        field: &'static str,
+        reason: &'static str,
+    },
+    MalformedChunk {
+        offset: usize,
+        chunk_type: [u8; 4],
+        reason: &'static str,
+    },
+    InvalidPayload {
+        reason: &'static str,
+    },
+    DecryptionFailed,
+    MissingPiece {
+        sequence: u32,
+        count: u32,
+    },
+    DuplicatePiece {
+        sequence: u32,
+        count: u32,
+    },
+    InvalidKey {
+        reason: &'static str,
+    },
+    UnknownSigner {
+        signer: String,
+    },
+    SignatureMismatch,
+    ChunkChanged {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    ChunkAdded {
+        offset: usize,
+        chunk_type: [u8; 4],
+    },
+    ChunkRemoved {
+        chunk_type: [u8; 4],
+        index: usize,
+    },
+    ChunksReordered,
+    InvalidChannels {
+        channels: String,
+    },
+    UnsupportedImage {
+        reason: &'static str,
+    },
+    CorruptImageData {
+        reason: &'static str,
+    },
+    NoHiddenMessage,
+    MessageTooLong {
+        length: usize,
+        capacity: usize,
+    },
+    ChunkNotFound {
+        chunk_type: String,
+    },
+    ChunkIndexOutOfRange {
+        index: usize,
+        len: usize,
+    },
+    InvalidUtf8(std::str::Utf8Error),
+    // io::Error can't be cloned or compared, so only its kind and message are
+    // kept.
+    Io {
+        kind: std::io::ErrorKind,
+        message: String,
+    },
+}
+
+impl Display for PngError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PngError::BadSignature { found } => write!(
+                f,
+                "Value provided was not a PNG -- did not start with the correct standard header value (found {:?})",
+                found
+            ),
+            PngError::TruncatedChunk {
+                offset,
+                needed,
+                available,
+            } => write!(
+                f,
+                "Chunk at byte {} is truncated: needed {} bytes but only {} remain",
+                offset, needed, available
+            ),
+            PngError::CrcMismatch {
+                offset,
+                chunk_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "The checksum for the {} chunk at byte {} is invalid: expected {:#010x}, found {:#010x}",
+                String::from_utf8_lossy(chunk_type),
+                offset,
+                expected,
+                actual
+            ),
+            PngError::InvalidChunkTypeByte { offset, byte } => write!(
+                f,
+                "Chunk type byte {} at byte {} is not ASCII alphabetic",
+                byte, offset
+            ),
+            PngError::InvalidChunkTypeLength { length } => write!(
+                f,
+                "Chunk type must be 4 bytes long, but was {} bytes long",
+                length
+            ),
+            PngError::LengthOverflow { offset, length } => write!(
+                f,
+                "Chunk length {} at byte {} is bigger than the largest length a PNG allows ({})",
+                length,
+                offset,
+                crate::chunk::Chunk::MAX_LENGTH
+            ),
+            PngError::TrailingBytes { offset, count } => write!(
+                f,
+                "Found {} unexpected bytes after the end of the data at byte {}",
+                count, offset
+            ),
+            PngError::MissingIhdr => write!(f, "The PNG does not start with an IHDR chunk"),
+            PngError::MissingIend => write!(f, "The PNG does not end with an IEND chunk"),
+            PngError::DuplicateChunk { offset, chunk_type } => write!(
+                f,
+                "The {} chunk at byte {} is a duplicate: only one is allowed",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::ChunkOutOfOrder {
+                offset,
+                chunk_type,
+                other,
+                before,
+            } => write!(
+                f,
+                "The {} chunk at byte {} must come {} the {} chunk",
+                String::from_utf8_lossy(chunk_type),
+                offset,
+                if *before { "before" } else { "after" },
+                String::from_utf8_lossy(other)
+            ),
+            PngError::SplitImageData { offset, chunk_type } => write!(
+                f,
+                "The IDAT chunks are split up by the {} chunk at byte {}",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::ChunkNotAllowed {
+                offset,
+                chunk_type,
+                colour_type,
+            } => write!(
+                f,
+                "The {} chunk at byte {} is not allowed in an image of colour type {}",
+                String::from_utf8_lossy(chunk_type),
+                offset,
+                colour_type
+            ),
+            PngError::InvalidChunkLength {
+                offset,
+                chunk_type,
+                length,
+            } => write!(
+                f,
+                "The {} chunk at byte {} has a length of {}, which is not valid for that type",
+                String::from_utf8_lossy(chunk_type),
+                offset,
+                length
+            ),
+            PngError::UnknownCriticalChunk { offset, chunk_type } => write!(
+                f,
+                "The {} chunk at byte {} is critical but of an unknown type, so decoders will reject the image",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::ReservedBitSet { offset, chunk_type } => write!(
+                f,
+                "The {} chunk at byte {} has the reserved bit of its type set",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::UnexpectedChunkType {
+                offset,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Expected a {} chunk at byte {}, but found a {} chunk",
+                String::from_utf8_lossy(expected),
+                offset,
+                String::from_utf8_lossy(found)
+            ),
+            PngError::InvalidIhdrField { field, value } => {
+                write!(f, "The IHDR chunk's {} of {} is not valid", field, value)
+            }
+            PngError::InvalidBitDepth {
+                bit_depth,
+                colour_type,
+            } => write!(
+                f,
+                "A bit depth of {} is not allowed for colour type {}",
+                bit_depth, colour_type
+            ),
+            PngError::InvalidKeyword { keyword } => write!(
+                f,
+                "{:?} is not a valid keyword: keywords are 1 to {} printable Latin-1 characters, with no spaces at either end or two in a row",
+                keyword,
+                crate::text::MAX_KEYWORD_LENGTH
+            ),
+            PngError::InvalidTextField { field, reason } => {
+                write!(f, "The {} {}", field, reason)
+            }
+            PngError::MalformedChunk {
+                offset,
+                chunk_type,
+                reason,
+            } => write!(
+                f,
+                "The {} chunk at byte {} is malformed: {}",
+                String::from_utf8_lossy(chunk_type),
+                offset,
+                reason
+            ),
+            PngError::InvalidPayload { reason } => {
+                write!(f, "The hidden message can't be read: {}", reason)
+            }
+            PngError::DecryptionFailed => write!(
+                f,
+                "The hidden message couldn't be decrypted: the key is wrong, or the message has been tampered with"
+            ),
+            PngError::MissingPiece { sequence, count } => write!(
+                f,
+                "The hidden message was split into {} pieces, but piece {} (counting from 0) is missing",
+                count, sequence
+            ),
+            PngError::DuplicatePiece { sequence, count } => write!(
+                f,
+                "The hidden message was split into {} pieces, but piece {} (counting from 0) is there more than once",
+                count, sequence
+            ),
+            PngError::InvalidKey { reason } => write!(f, "The key can't be used: {}", reason),
+            PngError::UnknownSigner { signer } => write!(
+                f,
+                "The PNG was signed by {}, which isn't one of the keys given",
+                signer
+            ),
+            PngError::SignatureMismatch => write!(
+                f,
+                "The signature is not valid: the signature chunk has been tampered with"
+            ),
+            PngError::ChunkChanged { offset, chunk_type } => write!(
+                f,
+                "The {} chunk at byte {} has been changed since the PNG was signed",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::ChunkAdded { offset, chunk_type } => write!(
+                f,
+                "The {} chunk at byte {} has been added since the PNG was signed",
+                String::from_utf8_lossy(chunk_type),
+                offset
+            ),
+            PngError::ChunkRemoved { chunk_type, index } => write!(
+                f,
+                "{} chunk {} (counting from 0) has been removed since the PNG was signed",
+                String::from_utf8_lossy(chunk_type),
+                index
+            ),
+            PngError::ChunksReordered => write!(
+                f,
+                "The signed chunks have been put in a different order since the PNG was signed"
+            ),
+            PngError::InvalidChannels { channels } => write!(
+                f,
+                "{:?} isn't a set of channels: use letters from rgba, such as rgb",
+                channels
+            ),
+            PngError::UnsupportedImage { reason } => {
+                write!(f, "Messages can't be hidden in this image's pixels: {}", reason)
+            }
+            PngError::CorruptImageData { reason } => {
+                write!(f, "The image data can't be read: {}", reason)
+            }
+            PngError::NoHiddenMessage => write!(
+                f,
+                "There is no message hidden in the image's pixels, at least not in those channels"
+            ),
+            PngError::MessageTooLong { length, capacity } => write!(
+                f,
+                "The message is {} bytes long, but only {} bytes fit in the image's pixels",
+                length, capacity
+            ),
+            PngError::ChunkNotFound { chunk_type } => {
+                write!(f, "There is no {} chunk in the PNG", chunk_type)
+            }
+            PngError::ChunkIndexOutOfRange { index, len } => write!(
+                f,
+                "There is no chunk at index {} (the PNG has {} chunks)",
+                index, len
+            ),
+            PngError::InvalidUtf8(err) => write!(f, "Chunk data is not valid UTF-8: {}", err),
+            PngError::Io { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for PngError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PngError::InvalidUtf8(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::str::Utf8Error> for PngError {
+    fn from(err: std::str::Utf8Error) -> Self {
+        PngError::InvalidUtf8(err)
+    }
+}
+
+impl From<std::io::Error> for PngError {
+    fn from(err: std::io::Error) -> Self {
+        PngError::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl PngError {
+    // Moves an error's offset along by `base` bytes, so an error found while
+    // parsing part of the input can point at its position in the whole input.
+    pub(crate) fn offset_by(self, base: usize) -> Self {
+        match self {
+            PngError::TruncatedChunk {
+                offset,
+                needed,
+                available,
+            } => PngError::TruncatedChunk {
+                offset: offset + base,
+                needed,
+                available,
+            },
+            PngError::CrcMismatch {
+                offset,
+                chunk_type,
+                expected,
+                actual,
+            } => PngError::CrcMismatch {
+                offset: offset + base,
+                chunk_type,
+                expected,
+                actual,
+            },
+            PngError::InvalidChunkTypeByte { offset, byte } => PngError::InvalidChunkTypeByte {
+                offset: offset + base,
+                byte,
+            },
+            PngError::LengthOverflow { offset, length } => PngError::LengthOverflow {
+                offset: offset + base,
+                length,
+            },
+            PngError::TrailingBytes { offset, count } => PngError::TrailingBytes {
+                offset: offset + base,
+                count,
+            },
+            err => err,
+        }
+    }
+
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            PngError::TruncatedChunk { offset, .. }
+            | PngError::CrcMismatch { offset, .. }
+            | PngError::InvalidChunkTypeByte { offset, .. }
+            | PngError::LengthOverflow { offset, .. }
+            | PngError::TrailingBytes { offset, .. }
+            | PngError::DuplicateChunk { offset, .. }
+            | PngError::ChunkOutOfOrder { offset, .. }
+            | PngError::SplitImageData { offset, .. }
+            | PngError::ChunkNotAllowed { offset, .. }
+            | PngError::InvalidChunkLength { offset, .. }
+            | PngError::ChunkChanged { offset, .. }
+            | PngError::ChunkAdded { offset, .. }
+            | PngError::UnknownCriticalChunk { offset, .. }
+            | PngError::ReservedBitSet { offset, .. }
+            | PngError::UnexpectedChunkType { offset, .. }
+            | PngError::MalformedChunk { offset, .. } => Some(*offset),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use crate::chunk::{Chunk, ChunkRef};
+use crate::chunk_type::ChunkType;
+use crate::error::PngError;
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColourType {
+    Greyscale = 0,
+    Truecolour = 2,
+    IndexedColour = 3,
+    GreyscaleAlpha = 4,
+    TruecolourAlpha = 6,
+}
+
+impl TryFrom<u8> for ColourType {
+    type Error = PngError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ColourType::Greyscale),
+            2 => Ok(ColourType::Truecolour),
+            3 => Ok(ColourType::IndexedColour),
+            4 => Ok(ColourType::GreyscaleAlpha),
+            6 => Ok(ColourType::TruecolourAlpha),
+            _ => Err(PngError::InvalidIhdrField {
+                field: "colour type",
+                value: value as u32,
+            }),
+        }
+    }
+}
+
+impl Display for ColourType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ColourType::Greyscale => "greyscale",
+            ColourType::Truecolour => "truecolour",
+            ColourType::IndexedColour => "indexed-colour",
+            ColourType::GreyscaleAlpha => "greyscale with alpha",
+            ColourType::TruecolourAlpha => "truecolour with alpha",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl ColourType {
+    // The bit depths the spec allows for each colour type.
+    pub fn allowed_bit_depths(&self) -> &'static [u8] {
+        match self {
+            ColourType::Greyscale => &[1, 2, 4, 8, 16],
+            ColourType::IndexedColour => &[1, 2, 4, 8],
+            ColourType::Truecolour | ColourType::GreyscaleAlpha | ColourType::TruecolourAlpha => {
+                &[8, 16]
+            }
+        }
+    }
+
+    // How many samples make up each pixel.
+    pub fn channels(&self) -> usize {
+        match self {
+            ColourType::Greyscale | ColourType::IndexedColour => 1,
+            ColourType::GreyscaleAlpha => 2,
+            ColourType::Truecolour => 3,
+            ColourType::TruecolourAlpha => 4,
+        }
+    }
+}
+
+// The image header: the first chunk of every PNG, describing the image's size
+// and how its pixels are stored.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ihdr {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    colour_type: ColourType,
+    interlaced: bool,
+}
+
+impl TryFrom<&Chunk> for Ihdr {
+    type Error = PngError;
+
+    fn try_from(value: &Chunk) -> Result<Self, Self::Error> {
+        Ihdr::from_data(value.chunk_type(), value.data(), 0)
+    }
+}
+
+impl TryFrom<ChunkRef<'_>> for Ihdr {
+    type Error = PngError;
+
+    fn try_from(value: ChunkRef<'_>) -> Result<Self, Self::Error> {
+        Ihdr::from_data(value.chunk_type(), value.data(), value.offset())
+    }
+}
+
+impl Display for Ihdr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}x{}, {}-bit {}, {}",
+            self.width,
+            self.height,
+            self.bit_depth,
+            self.colour_type,
+            if self.interlaced {
+                "interlaced"
+            } else {
+                "not interlaced"
+            }
+        )
+    }
+}
+
+impl Ihdr {
+    pub const LENGTH: usize = 13;
+
+    // The largest width or height a PNG allows.
+    pub const MAX_DIMENSION: u32 = (1 << 31) - 1;
+
+    pub fn new(
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+        colour_type: ColourType,
+        interlaced: bool,
+    ) -> Result<Ihdr, PngError> {
+        for (field, value) in [("width", width), ("height", height)] {
+            if value == 0 || value > Self::MAX_DIMENSION {
+                return Err(PngError::InvalidIhdrField { field, value });
+            }
+        }
+        if !colour_type.allowed_bit_depths().contains(&bit_depth) {
+            return Err(PngError::InvalidBitDepth {
+                bit_depth,
+                colour_type: colour_type as u8,
+            });
+        }
+
+        Ok(Ihdr {
+            width,
+            height,
+            bit_depth,
+            colour_type,
+            interlaced,
+        })
+    }
+
+    // `offset` is where the chunk starts, for errors to point at.
+    fn from_data(chunk_type: &ChunkType, data: &[u8], offset: usize) -> Result<Ihdr, PngError> {
+        if &chunk_type.bytes() != b"IHDR" {
+            return Err(PngError::UnexpectedChunkType {
+                offset,
+                expected: *b"IHDR",
+                found: chunk_type.bytes(),
+            });
+        }
+        if data.len() != Self::LENGTH {
+            return Err(PngError::InvalidChunkLength {
+                offset,
+                chunk_type: *b"IHDR",
+                length: data.len(),
+            });
+        }
+
+        // Only one compression method (deflate) and one filter method (adaptive
+        // filtering with five filter types) have ever been defined.
+        for (field, value) in [
+            ("compression method", data[10]),
+            ("filter method", data[11]),
+        ] {
+            if value != 0 {
+                return Err(PngError::InvalidIhdrField {
+                    field,
+                    value: value as u32,
+                });
+            }
+        }
+        let interlaced = match data[12] {
+            0 => false,
+            1 => true,
+            value => {
+                return Err(PngError::InvalidIhdrField {
+                    field: "interlace method",
+                    value: value as u32,
+                })
+            }
+        };
+
+        Ihdr::new(
+            u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+            u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+            data[8],
+            ColourType::try_from(data[9])?,
+            interlaced,
+        )
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bit_depth(&self) -> u8 {
+        self.bit_depth
+    }
+
+    pub fn colour_type(&self) -> ColourType {
+        self.colour_type
+    }
+
+    pub fn compression_method(&self) -> u8 {
+        0
+    }
+
+    pub fn filter_method(&self) -> u8 {
+        0
+    }
+
+    // 0 for none, 1 for Adam7.
+    pub fn interlace_method(&self) -> u8 {
+        self.interlaced as u8
+    }
+
+    pub fn is_interlaced(&self) -> bool {
+        self.interlaced
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = Vec::with_capacity(Self::LENGTH);
+        data.extend_from_slice(&self.width.to_be_bytes());
+        data.extend_from_slice(&self.height.to_be_bytes());
+        data.extend_from_slice(&[
+            self.bit_depth,
+            self.colour_type as u8,
+            self.compression_method(),
+            self.filter_method(),
+            self.interlace_method(),
+        ]);
+        Chunk::new(ChunkType::from_str("IHDR").unwrap(), data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ihdr_chunk(data: &[u8]) -> Chunk {
+        Chunk::new(ChunkType::from_str("IHDR").unwrap(), data.to_vec())
+    }
+
+    #[test]
+    fn test_ihdr_from_chunk() {
+        let chunk = ihdr_chunk(&[0, 0, 1, 0, 0, 0, 0, 50, 16, 6, 0, 0, 1]);
+        let ihdr = Ihdr::try_from(&chunk).unwrap();
+
+        assert_eq!(ihdr.width(), 256);
+        assert_eq!(ihdr.height(), 50);
+        assert_eq!(ihdr.bit_depth(), 16);
+        assert_eq!(ihdr.colour_type(), ColourType::TruecolourAlpha);
+        assert_eq!(ihdr.interlace_method(), 1);
+        assert!(ihdr.is_interlaced());
+        assert!(ihdr.to_chunk() == chunk);
+    }
+
+    #[test]
+    fn test_ihdr_display() {
+        let ihdr = Ihdr::new(50, 40, 8, ColourType::IndexedColour, false).unwrap();
+        assert_eq!(
+            ihdr.to_string(),
+            "50x40, 8-bit indexed-colour, not interlaced"
+        );
+    }
+
+    #[test]
+    fn test_ihdr_bit_depths() {
+        assert!(Ihdr::new(1, 1, 1, ColourType::Greyscale, false).is_ok());
+        assert!(Ihdr::new(1, 1, 16, ColourType::Greyscale, false).is_ok());
+        assert!(Ihdr::new(1, 1, 4, ColourType::IndexedColour, false).is_ok());
+        assert_eq!(
+            Ihdr::new(1, 1, 16, ColourType::IndexedColour, false),
+            Err(PngError::InvalidBitDepth {
+                bit_depth: 16,
+                colour_type: 3,
+            })
+        );
+        assert!(Ihdr::new(1, 1, 4, ColourType::Truecolour, false).is_err());
+        assert!(Ihdr::new(1, 1, 3, ColourType::Greyscale, false).is_err());
+    }
+
+    #[test]
+    fn test_invalid_ihdr() {
+        let invalid = |data: &[u8]| Ihdr::try_from(&ihdr_chunk(data)).unwrap_err();
+
+        assert_eq!(
+            invalid(&[0, 0, 0, 0, 0, 0, 0, 1, 8, 2, 0, 0, 0]),
+            PngError::InvalidIhdrField {
+                field: "width",
+                value: 0,
+            }
+        );
+        assert_eq!(
+            invalid(&[0, 0, 0, 1, 128, 0, 0, 0, 8, 2, 0, 0, 0]),
+            PngError::InvalidIhdrField {
+                field: "height",
+                value: 1 << 31,
+            }
+        );
+        assert_eq!(
+            invalid(&[0, 0, 0, 1, 0, 0, 0, 1, 8, 5, 0, 0, 0]),
+            PngError::InvalidIhdrField {
+                field: "colour type",
+                value: 5,
+            }
+        );
+        assert_eq!(
+            invalid(&[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 1, 0, 0]),
+            PngError::InvalidIhdrField {
+                field: "compression method",
+                value: 1,
+            }
+        );
+        assert_eq!(
+            invalid(&[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 2]),
+            PngError::InvalidIhdrField {
+                field: "interlace method",
+                value: 2,
+            }
+        );
+        assert_eq!(
+            invalid(&[0, 0, 0, 1]),
+            PngError::InvalidChunkLength {
+                offset: 0,
+                chunk_type: *b"IHDR",
+                length: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn test_ihdr_from_wrong_chunk() {
+        let chunk = Chunk::new(ChunkType::from_str("IEND").unwrap(), Vec::new());
+
+        assert_eq!(
+            Ihdr::try_from(chunk.as_chunk_ref(40)),
+            Err(PngError::UnexpectedChunkType {
+                offset: 40,
+                expected: *b"IHDR",
+                found: *b"IEND",
+            })
+        );
+    }
+}
@@ -0,0 +1,27 @@
+//! Reading and writing the chunks of PNG files.
+//!
+//! The `pngme` binary is a thin command-line wrapper around this library.
+
+pub mod chunk;
+pub mod chunk_type;
+pub mod encrypt;
+pub mod error;
+pub mod ihdr;
+pub mod lsb;
+pub mod payload;
+pub mod png;
+pub mod recipient;
+pub mod signature;
+pub mod stream;
+pub mod text;
+mod zlib;
+
+pub use chunk::{Chunk, ChunkRef};
+pub use chunk_type::ChunkType;
+pub use error::PngError;
+pub use ihdr::{ColourType, Ihdr};
+pub use png::{Png, PngRef};
+pub use recipient::{Identity, Recipient};
+pub use signature::{SigningKey, VerifyingKey};
+pub use stream::{ChunkReader, ChunkWriter};
+pub use text::{CompressedTextChunk, InternationalTextChunk, TextChunk, TextualChunk};
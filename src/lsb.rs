@@ -0,0 +1,487 @@
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::error::PngError;
+use crate::ihdr::{ColourType, Ihdr};
+use crate::png::Png;
+use crate::zlib;
+use std::str::FromStr;
+
+// Rather than going in a chunk of its own, a message can be hidden in the
+// least significant bit of the image's samples, which changes each one by at
+// most 1 part in 256. The bits go in one at a time, most significant first,
+// through the chosen channels of each pixel, pixel by pixel and row by row.
+// They start with this header:
+//
+//   magic (4)    'p' 'm' 'e' 'L'
+//   length (4)   of the message, big-endian
+//
+// Only 8 and 16-bit images that aren't interlaced or palette-based can hold a
+// message: in the rest a sample's low bit matters too much to the picture.
+const MAGIC: [u8; 4] = *b"pmeL";
+pub const HEADER_LENGTH: usize = 8;
+// How long the IDAT chunks are that the image data is written back out in.
+const IDAT_LENGTH: usize = 1 << 16;
+
+// Which channels' samples hold the message. In greyscale images the grey
+// channel stands in for each of red, green and blue.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Channels {
+    pub red: bool,
+    pub green: bool,
+    pub blue: bool,
+    pub alpha: bool,
+}
+
+// Alpha is left alone unless asked for, since changing it can show up as a
+// faint outline where a transparent area meets an opaque one.
+impl Default for Channels {
+    fn default() -> Self {
+        Channels {
+            red: true,
+            green: true,
+            blue: true,
+            alpha: false,
+        }
+    }
+}
+
+// Letters from "rgba", in any order.
+impl FromStr for Channels {
+    type Err = PngError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || PngError::InvalidChannels {
+            channels: s.to_string(),
+        };
+        if s.is_empty() || !s.chars().all(|c| "rgba".contains(c)) {
+            return Err(invalid());
+        }
+        Ok(Channels {
+            red: s.contains('r'),
+            green: s.contains('g'),
+            blue: s.contains('b'),
+            alpha: s.contains('a'),
+        })
+    }
+}
+
+impl std::fmt::Display for Channels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (used, letter) in [
+            (self.red, 'r'),
+            (self.green, 'g'),
+            (self.blue, 'b'),
+            (self.alpha, 'a'),
+        ] {
+            if used {
+                write!(f, "{}", letter)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Channels {
+    // Whether each sample of a pixel is used, in the order they're stored.
+    fn mask(&self, colour_type: ColourType) -> Vec<bool> {
+        let grey = self.red || self.green || self.blue;
+        match colour_type {
+            ColourType::Greyscale | ColourType::IndexedColour => vec![grey],
+            ColourType::GreyscaleAlpha => vec![grey, self.alpha],
+            ColourType::Truecolour => vec![self.red, self.green, self.blue],
+            ColourType::TruecolourAlpha => vec![self.red, self.green, self.blue, self.alpha],
+        }
+    }
+}
+
+// How many bytes of message fit in the pixels of `png`.
+pub fn capacity(png: &Png, channels: Channels) -> Result<usize, PngError> {
+    let image = Image::read(png)?;
+    Ok(image.capacity(&image.lsb_offsets(channels)?))
+}
+
+// Hides `message` in the pixels of `png`, replacing its IDAT chunks with ones
+// holding the changed image. Anything hidden there before is overwritten.
+pub fn embed(png: &mut Png, message: &[u8], channels: Channels) -> Result<(), PngError> {
+    let mut image = Image::read(png)?;
+    let offsets = image.lsb_offsets(channels)?;
+    let capacity = image.capacity(&offsets);
+    if message.len() > capacity {
+        return Err(PngError::MessageTooLong {
+            length: message.len(),
+            capacity,
+        });
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_LENGTH + message.len());
+    bytes.extend_from_slice(&MAGIC);
+    bytes.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(message);
+    let bits = bytes
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1));
+    for (index, bit) in bits.enumerate() {
+        let position = image.position(&offsets, index);
+        image.data[position] = image.data[position] & !1 | bit;
+    }
+    image.write_to(png)
+}
+
+// Reads back a message hidden by `embed` with the same channels.
+pub fn extract(png: &Png, channels: Channels) -> Result<Vec<u8>, PngError> {
+    let image = Image::read(png)?;
+    let offsets = image.lsb_offsets(channels)?;
+    let capacity = image.capacity(&offsets);
+    let byte_at = |index: usize| {
+        (0..8).fold(0, |byte, bit| {
+            byte << 1 | image.data[image.position(&offsets, index * 8 + bit)] & 1
+        })
+    };
+
+    let total_bytes = image.sample_count(&offsets) / 8;
+    if total_bytes < HEADER_LENGTH || (0..4).map(byte_at).ne(MAGIC) {
+        return Err(PngError::NoHiddenMessage);
+    }
+    let length = u32::from_be_bytes([byte_at(4), byte_at(5), byte_at(6), byte_at(7)]) as usize;
+    if length > capacity {
+        return Err(PngError::CorruptImageData {
+            reason: "the hidden message says it is longer than the pixels can hold",
+        });
+    }
+    Ok((HEADER_LENGTH..HEADER_LENGTH + length)
+        .map(byte_at)
+        .collect())
+}
+
+// The image's samples, with each row's filter undone. Every row still starts
+// with the byte saying which filter it was stored with, so that it can be
+// stored the same way again.
+struct Image {
+    ihdr: Ihdr,
+    data: Vec<u8>,
+    // bytes in a row, not counting the filter byte
+    stride: usize,
+    bytes_per_pixel: usize,
+    bytes_per_sample: usize,
+}
+
+impl Image {
+    fn read(png: &Png) -> Result<Image, PngError> {
+        let ihdr = png.ihdr()?;
+        let unsupported = |reason| Err(PngError::UnsupportedImage { reason });
+        if ihdr.colour_type() == ColourType::IndexedColour {
+            return unsupported(
+                "its pixels are palette indices, so changing them changes colours completely",
+            );
+        }
+        if ihdr.bit_depth() < 8 {
+            return unsupported("its samples are smaller than a byte");
+        }
+        if ihdr.is_interlaced() {
+            return unsupported("it is interlaced");
+        }
+
+        let bytes_per_sample = ihdr.bit_depth() as usize / 8;
+        let bytes_per_pixel = ihdr.colour_type().channels() * bytes_per_sample;
+        let too_big = PngError::UnsupportedImage {
+            reason: "it is too big",
+        };
+        let stride = (ihdr.width() as usize)
+            .checked_mul(bytes_per_pixel)
+            .ok_or(too_big.clone())?;
+        let length = (stride + 1)
+            .checked_mul(ihdr.height() as usize)
+            .ok_or(too_big)?;
+
+        let compressed: Vec<u8> = png
+            .chunks_by_type("IDAT")
+            .iter()
+            .flat_map(|chunk| chunk.data())
+            .copied()
+            .collect();
+        if compressed.is_empty() {
+            return Err(PngError::ChunkNotFound {
+                chunk_type: "IDAT".to_string(),
+            });
+        }
+        let mut data = zlib::decompress_at_most(&compressed, length)
+            .map_err(|reason| PngError::CorruptImageData { reason })?;
+        if data.len() < length {
+            return Err(PngError::CorruptImageData {
+                reason: "there is less of it than the image header says there should be",
+            });
+        }
+        data.truncate(length);
+
+        let mut image = Image {
+            ihdr,
+            data,
+            stride,
+            bytes_per_pixel,
+            bytes_per_sample,
+        };
+        image.unfilter()?;
+        Ok(image)
+    }
+
+    // Where the low byte of each used sample is within a pixel. For 16-bit
+    // samples that's the second byte, as they're big-endian.
+    fn lsb_offsets(&self, channels: Channels) -> Result<Vec<usize>, PngError> {
+        let offsets: Vec<usize> = channels
+            .mask(self.ihdr.colour_type())
+            .iter()
+            .enumerate()
+            .filter(|(_, used)| **used)
+            .map(|(channel, _)| (channel + 1) * self.bytes_per_sample - 1)
+            .collect();
+        if offsets.is_empty() {
+            return Err(PngError::UnsupportedImage {
+                reason: "it has none of the channels asked for",
+            });
+        }
+        Ok(offsets)
+    }
+
+    fn sample_count(&self, offsets: &[usize]) -> usize {
+        self.ihdr.width() as usize * self.ihdr.height() as usize * offsets.len()
+    }
+
+    fn capacity(&self, offsets: &[usize]) -> usize {
+        (self.sample_count(offsets) / 8).saturating_sub(HEADER_LENGTH)
+    }
+
+    // Where in `data` the `index`th bit of the message goes.
+    fn position(&self, offsets: &[usize], index: usize) -> usize {
+        let pixel = index / offsets.len();
+        let width = self.ihdr.width() as usize;
+        let (y, x) = (pixel / width, pixel % width);
+        y * (self.stride + 1) + 1 + x * self.bytes_per_pixel + offsets[index % offsets.len()]
+    }
+
+    fn unfilter(&mut self) -> Result<(), PngError> {
+        let row_length = self.stride + 1;
+        for start in (0..self.data.len()).step_by(row_length) {
+            let (before, rest) = self.data.split_at_mut(start);
+            let previous = &before[before.len().saturating_sub(self.stride)..];
+            let (filter, row) = rest[..row_length].split_first_mut().unwrap();
+            if *filter > 4 {
+                return Err(PngError::CorruptImageData {
+                    reason: "a row uses a filter that doesn't exist",
+                });
+            }
+            for x in 0..self.stride {
+                let (left, up, up_left) = neighbours(row, previous, x, self.bytes_per_pixel);
+                row[x] = row[x].wrapping_add(predict(*filter, left, up, up_left));
+            }
+        }
+        Ok(())
+    }
+
+    // Filters each row again, the same way it was filtered before, and puts
+    // the result in place of `png`'s IDAT chunks.
+    fn write_to(self, png: &mut Png) -> Result<(), PngError> {
+        let row_length = self.stride + 1;
+        let mut filtered = self.data.clone();
+        for start in (0..self.data.len()).step_by(row_length) {
+            let previous = &self.data[start.saturating_sub(self.stride)..start];
+            let filter = self.data[start];
+            let row = &self.data[start + 1..start + row_length];
+            for x in 0..self.stride {
+                let (left, up, up_left) = neighbours(row, previous, x, self.bytes_per_pixel);
+                filtered[start + 1 + x] = row[x].wrapping_sub(predict(filter, left, up, up_left));
+            }
+        }
+
+        let index = png.chunk_indices("IDAT")[0];
+        png.remove_chunks("IDAT");
+        let chunk_type = ChunkType::from_str("IDAT")?;
+        for (n, data) in zlib::compress(&filtered).chunks(IDAT_LENGTH).enumerate() {
+            png.insert_at(index + n, Chunk::new(chunk_type, data.to_vec()))?;
+        }
+        Ok(())
+    }
+}
+
+// The unfiltered bytes to the left of, above, and above and to the left of
+// byte `x` of `row`, counting as 0 off the edge of the image.
+fn neighbours(row: &[u8], previous: &[u8], x: usize, bytes_per_pixel: usize) -> (u8, u8, u8) {
+    let left = x.checked_sub(bytes_per_pixel);
+    (
+        left.map_or(0, |left| row[left]),
+        previous.get(x).copied().unwrap_or(0),
+        left.and_then(|left| previous.get(left))
+            .copied()
+            .unwrap_or(0),
+    )
+}
+
+// What each of the five filters predicts a byte to be, from its neighbours.
+fn predict(filter: u8, left: u8, up: u8, up_left: u8) -> u8 {
+    match filter {
+        1 => left,
+        2 => up,
+        3 => ((left as u16 + up as u16) / 2) as u8,
+        4 => {
+            let estimate = left as i16 + up as i16 - up_left as i16;
+            let distance = |byte: u8| (estimate - byte as i16).abs();
+            if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+                left
+            } else if distance(up) <= distance(up_left) {
+                up
+            } else {
+                up_left
+            }
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A `width` by `height` image whose rows use each of the five filters in
+    // turn, with samples that vary enough for the filters to matter.
+    fn testing_png(width: u32, height: u32, bit_depth: u8, colour_type: u8) -> Png {
+        let ihdr = Ihdr::new(
+            width,
+            height,
+            bit_depth,
+            ColourType::try_from(colour_type).unwrap(),
+            false,
+        )
+        .unwrap();
+        let stride = width as usize * ihdr.colour_type().channels() * bit_depth as usize / 8;
+        let mut raw = Vec::new();
+        for y in 0..height as usize {
+            raw.push((y % 5) as u8);
+            raw.extend((0..stride).map(|x| (x * 37 + y * 101) as u8));
+        }
+        Png::from_chunks(vec![
+            ihdr.to_chunk(),
+            Chunk::new(ChunkType::from_str("IDAT").unwrap(), zlib::compress(&raw)),
+            Chunk::new(ChunkType::from_str("IEND").unwrap(), Vec::new()),
+        ])
+    }
+
+    #[test]
+    fn test_filters_round_trip() {
+        let mut png = testing_png(7, 10, 8, 6);
+        let before = Image::read(&png).unwrap().data;
+        let raw = zlib::decompress_at_most(png.chunks()[1].data(), usize::MAX).unwrap();
+        assert_ne!(before, raw);
+
+        Image::read(&png).unwrap().write_to(&mut png).unwrap();
+        assert_eq!(
+            zlib::decompress_at_most(png.chunks()[1].data(), usize::MAX).unwrap(),
+            raw
+        );
+        assert_eq!(Image::read(&png).unwrap().data, before);
+    }
+
+    #[test]
+    fn test_embed_and_extract() {
+        let mut png = testing_png(16, 8, 8, 6);
+        let before = Image::read(&png).unwrap().data;
+        // 16 * 8 pixels, 3 bits each, less the header
+        assert_eq!(capacity(&png, Channels::default()), Ok(40));
+
+        embed(&mut png, b"meet at dawn", Channels::default()).unwrap();
+        assert_eq!(extract(&png, Channels::default()).unwrap(), b"meet at dawn");
+        assert_eq!(
+            png.chunks().len(),
+            3,
+            "the image data is rewritten in place"
+        );
+
+        let after = Image::read(&png).unwrap().data;
+        for (index, (old, new)) in before.iter().zip(&after).enumerate() {
+            assert!(old.abs_diff(*new) <= 1);
+            if index % 65 != 0 && (index % 65 - 1) % 4 == 3 {
+                assert_eq!(old, new, "alpha is left alone");
+            }
+        }
+    }
+
+    #[test]
+    fn test_channels() {
+        assert_eq!("rgb".parse(), Ok(Channels::default()));
+        let alpha: Channels = "ab".parse().unwrap();
+        assert_eq!(alpha.to_string(), "ba");
+        assert!("".parse::<Channels>().is_err());
+        assert!("rgbx".parse::<Channels>().is_err());
+
+        let mut png = testing_png(32, 8, 8, 6);
+        embed(&mut png, b"in the alpha", "a".parse().unwrap()).unwrap();
+        assert_eq!(
+            extract(&png, "a".parse().unwrap()).unwrap(),
+            b"in the alpha"
+        );
+        assert_eq!(
+            extract(&png, Channels::default()),
+            Err(PngError::NoHiddenMessage)
+        );
+        assert!(capacity(&testing_png(4, 4, 8, 2), "a".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_other_formats() {
+        for (bit_depth, colour_type, capacity) in [(16, 0, 16), (8, 4, 16), (16, 2, 64)] {
+            let mut png = testing_png(16, 12, bit_depth, colour_type);
+            assert_eq!(super::capacity(&png, Channels::default()), Ok(capacity));
+            embed(&mut png, b"hello", Channels::default()).unwrap();
+            assert_eq!(extract(&png, Channels::default()).unwrap(), b"hello");
+        }
+    }
+
+    #[test]
+    fn test_too_long() {
+        let mut png = testing_png(16, 8, 8, 2);
+        assert_eq!(
+            embed(&mut png, &[0; 41], Channels::default()),
+            Err(PngError::MessageTooLong {
+                length: 41,
+                capacity: 40
+            })
+        );
+    }
+
+    #[test]
+    fn test_unsupported_images() {
+        for (bit_depth, colour_type) in [(8, 3), (4, 0)] {
+            assert!(matches!(
+                capacity(
+                    &testing_png(8, 8, bit_depth, colour_type),
+                    Channels::default()
+                ),
+                Err(PngError::UnsupportedImage { .. })
+            ));
+        }
+
+        let mut interlaced = testing_png(8, 8, 8, 2);
+        let ihdr = Ihdr::new(8, 8, 8, ColourType::Truecolour, true).unwrap();
+        interlaced.remove_chunk("IHDR").unwrap();
+        interlaced.insert_chunk(ihdr.to_chunk());
+        assert!(matches!(
+            capacity(&interlaced, Channels::default()),
+            Err(PngError::UnsupportedImage { .. })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_image_data() {
+        let chunk = |data: &[u8]| Chunk::new(ChunkType::from_str("IDAT").unwrap(), data.to_vec());
+        let ihdr = Ihdr::new(2, 2, 8, ColourType::Greyscale, false).unwrap();
+        for data in [
+            zlib::compress(&[0, 1, 2]),
+            zlib::compress(&[0, 1, 2, 5, 3, 4]),
+            b"not zlib".to_vec(),
+        ] {
+            let png = Png::from_chunks(vec![ihdr.to_chunk(), chunk(&data)]);
+            assert!(matches!(
+                capacity(&png, Channels::default()),
+                Err(PngError::CorruptImageData { .. })
+            ));
+        }
+    }
+}
@@ -1,8 +1,6 @@
 mod args;
-mod chunk;
-mod chunk_type;
+mod atomic;
 mod commands;
-mod png;
 
 fn main() {
     if let Err(err) = args::Config::new().and_then(args::Config::run) {
@@ -0,0 +1,433 @@
+use crate::chunk::CRC;
+use crate::error::PngError;
+use crate::zlib;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+
+// Messages that pngme has done something to before hiding (compressed them,
+// say) are wrapped in layers, each of which starts with this header:
+//
+//   magic (4 bytes)  0x89 'p' 'm' 'e'
+//   version (1)      of the header, currently 1
+//   layer (1)        what was done to the rest: see `Layer`
+//
+// The first magic byte can't start a UTF-8 string, so a plain text message is
+// never mistaken for a wrapped one, and any other message that starts with the
+// magic is itself wrapped, in an `Escaped` layer. Layers can be nested,
+// outermost first, but only in the order encode wraps them: see
+// `Layer::can_contain`.
+pub const MAGIC: [u8; 4] = [0x89, b'p', b'm', b'e'];
+pub const VERSION: u8 = 1;
+pub const HEADER_LENGTH: usize = MAGIC.len() + 2;
+
+// The most a compressed message may inflate to unless decode is told
+// otherwise, so that a small chunk can't use up all the memory there is.
+pub const MAX_MESSAGE_LENGTH: usize = 16 << 20;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layer {
+    // the rest is a zlib stream
+    Compressed = 1,
+    // the rest is encrypted with a key made from a passphrase: see `encrypt`
+    Passphrase = 2,
+    // the rest is encrypted to one or more public keys: see `recipient`
+    Recipients = 3,
+    // the rest is one piece of a message too big for one chunk: see `split`
+    Split = 4,
+    // the rest is the message itself, which starts with the magic: see `escape`
+    Escaped = 5,
+}
+
+impl TryFrom<u8> for Layer {
+    type Error = PngError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Layer::Compressed),
+            2 => Ok(Layer::Passphrase),
+            3 => Ok(Layer::Recipients),
+            4 => Ok(Layer::Split),
+            5 => Ok(Layer::Escaped),
+            _ => Err(PngError::InvalidPayload {
+                reason: "it uses a kind of layer this version of pngme doesn't know",
+            }),
+        }
+    }
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::Compressed => write!(f, "zlib"),
+            Layer::Passphrase => write!(f, "passphrase"),
+            Layer::Recipients => write!(f, "recipients"),
+            Layer::Split => write!(f, "split"),
+            Layer::Escaped => write!(f, "escaped"),
+        }
+    }
+}
+
+impl Layer {
+    // How deep encode puts the layer, outermost first: a message is escaped,
+    // then compressed, then encrypted (one way or the other), then split.
+    fn depth(self) -> u8 {
+        match self {
+            Layer::Split => 0,
+            Layer::Passphrase | Layer::Recipients => 1,
+            Layer::Compressed => 2,
+            Layer::Escaped => 3,
+        }
+    }
+
+    // Whether encode could have put `inner` directly inside this layer. Each
+    // kind of layer is only ever used once, which is what stops a message
+    // from being unwrapped over and over again.
+    pub fn can_contain(self, inner: Layer) -> bool {
+        self.depth() < inner.depth()
+    }
+}
+
+// Which layer `data` is wrapped in, or None if it's a plain message.
+pub fn layer(data: &[u8]) -> Result<Option<Layer>, PngError> {
+    match data {
+        [m0, m1, m2, m3, version, layer, ..] if [*m0, *m1, *m2, *m3] == MAGIC => {
+            if *version != VERSION {
+                return Err(PngError::InvalidPayload {
+                    reason: "it was written by a newer version of pngme",
+                });
+            }
+            Layer::try_from(*layer).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn wrap(layer: Layer, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(HEADER_LENGTH + body.len());
+    data.extend_from_slice(&MAGIC);
+    data.extend_from_slice(&[VERSION, layer as u8]);
+    data.extend_from_slice(body);
+    data
+}
+
+// What's inside the header of a wrapped message, checking that it is the
+// expected kind of layer.
+pub fn body(data: &[u8], expected: Layer) -> Result<&[u8], PngError> {
+    match layer(data)? {
+        Some(layer) if layer == expected => Ok(&data[HEADER_LENGTH..]),
+        _ => Err(PngError::InvalidPayload {
+            reason: "it isn't wrapped the way it was expected to be",
+        }),
+    }
+}
+
+// Wraps a message that starts with the magic, so that decode doesn't take it
+// for one with layers. Any other message is left as it is.
+pub fn escape(message: Vec<u8>) -> Vec<u8> {
+    if message.starts_with(&MAGIC) {
+        wrap(Layer::Escaped, &message)
+    } else {
+        message
+    }
+}
+
+pub fn compress(message: &[u8]) -> Vec<u8> {
+    wrap(Layer::Compressed, &zlib::compress(message))
+}
+
+// Refuses to inflate to more than `max_length` bytes.
+pub fn decompress(data: &[u8], max_length: usize) -> Result<Vec<u8>, PngError> {
+    zlib::decompress_at_most(body(data, Layer::Compressed)?, max_length)
+        .map_err(|reason| PngError::InvalidPayload { reason })
+}
+
+// Each piece of a split message is wrapped in a layer whose body is:
+//
+//   id (8)           picked at random for each message, the same in every piece
+//   sequence (4)     which piece this is, counting from 0, big-endian
+//   count (4)        how many pieces there are in all
+//   checksum (4)     CRC-32 of the whole message, the same in every piece
+//   data
+//
+// The id tells pieces of different messages apart, even when the same message
+// has been split twice. The checksum catches pieces that have been changed or
+// put back together wrongly.
+pub const PIECE_HEADER_LENGTH: usize = HEADER_LENGTH + 20;
+
+// One piece of a split message, as read by `piece`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Piece<'a> {
+    pub id: u64,
+    pub sequence: u32,
+    pub count: u32,
+    pub checksum: u32,
+    pub data: &'a [u8],
+}
+
+// Cuts `message` into pieces that are each at most `max_length` bytes long,
+// header included.
+pub fn split(message: &[u8], max_length: usize) -> Result<Vec<Vec<u8>>, PngError> {
+    if max_length <= PIECE_HEADER_LENGTH {
+        return Err(PngError::InvalidPayload {
+            reason: "pieces that small can't hold any of it",
+        });
+    }
+    let pieces: Vec<&[u8]> = message.chunks(max_length - PIECE_HEADER_LENGTH).collect();
+    let count = u32::try_from(pieces.len().max(1)).map_err(|_| PngError::InvalidPayload {
+        reason: "it would take too many pieces",
+    })?;
+    let id = OsRng.next_u64();
+    let checksum = CRC.checksum(message);
+    Ok((0..count)
+        .map(|sequence| {
+            let data = pieces.get(sequence as usize).copied().unwrap_or_default();
+            let mut body = Vec::with_capacity(20 + data.len());
+            body.extend_from_slice(&id.to_be_bytes());
+            body.extend_from_slice(&sequence.to_be_bytes());
+            body.extend_from_slice(&count.to_be_bytes());
+            body.extend_from_slice(&checksum.to_be_bytes());
+            body.extend_from_slice(data);
+            wrap(Layer::Split, &body)
+        })
+        .collect())
+}
+
+impl Piece<'_> {
+    // What every piece of the same message has in common.
+    pub fn message(&self) -> (u64, u32, u32) {
+        (self.id, self.count, self.checksum)
+    }
+}
+
+pub fn piece(data: &[u8]) -> Result<Piece<'_>, PngError> {
+    let body = body(data, Layer::Split)?;
+    if body.len() < 20 {
+        return Err(PngError::InvalidPayload {
+            reason: "its piece header is cut short",
+        });
+    }
+    let u32_at = |i: usize| u32::from_be_bytes(body[i..i + 4].try_into().unwrap());
+    let piece = Piece {
+        id: u64::from_be_bytes(body[..8].try_into().unwrap()),
+        sequence: u32_at(8),
+        count: u32_at(12),
+        checksum: u32_at(16),
+        data: &body[20..],
+    };
+    if piece.sequence >= piece.count {
+        return Err(PngError::InvalidPayload {
+            reason: "its piece number is past the number of pieces",
+        });
+    }
+    Ok(piece)
+}
+
+// Puts a split message back together from its pieces, which can be in any
+// order. Every piece has to be there exactly once.
+pub fn join<'a>(pieces: impl IntoIterator<Item = &'a [u8]>) -> Result<Vec<u8>, PngError> {
+    let mut pieces = pieces
+        .into_iter()
+        .map(piece)
+        .collect::<Result<Vec<_>, _>>()?;
+    let first = *pieces.first().ok_or(PngError::InvalidPayload {
+        reason: "none of its pieces were found",
+    })?;
+    if pieces
+        .iter()
+        .any(|piece| piece.message() != first.message())
+    {
+        return Err(PngError::InvalidPayload {
+            reason: "its pieces come from more than one message",
+        });
+    }
+
+    // Sorted, with no duplicates and every number below the count, the pieces
+    // are all there if there are as many of them as the count says.
+    pieces.sort_by_key(|piece| piece.sequence);
+    if let Some(pair) = pieces
+        .windows(2)
+        .find(|pair| pair[0].sequence == pair[1].sequence)
+    {
+        return Err(PngError::DuplicatePiece {
+            sequence: pair[0].sequence,
+            count: first.count,
+        });
+    }
+    if pieces.len() != first.count as usize {
+        let sequence = (0..first.count)
+            .zip(&pieces)
+            .find(|(sequence, piece)| piece.sequence != *sequence)
+            .map_or(pieces.len() as u32, |(sequence, _)| sequence);
+        return Err(PngError::MissingPiece {
+            sequence,
+            count: first.count,
+        });
+    }
+
+    let message: Vec<u8> = pieces
+        .iter()
+        .flat_map(|piece| piece.data)
+        .copied()
+        .collect();
+    if CRC.checksum(&message) != first.checksum {
+        return Err(PngError::InvalidPayload {
+            reason: "its pieces don't match the checksum they were split with",
+        });
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let message = "This is where your secret message will be! ".repeat(20);
+        let compressed = compress(message.as_bytes());
+
+        assert!(compressed.len() < message.len() / 4);
+        assert!(compressed.starts_with(&[0x89, b'p', b'm', b'e', 1, 1]));
+        assert_eq!(layer(&compressed), Ok(Some(Layer::Compressed)));
+        assert_eq!(
+            decompress(&compressed, MAX_MESSAGE_LENGTH).unwrap(),
+            message.as_bytes()
+        );
+        assert_eq!(
+            decompress(&compressed, 10),
+            Err(PngError::InvalidPayload {
+                reason: "its compressed data inflates to more than is allowed"
+            })
+        );
+    }
+
+    #[test]
+    fn test_plain_messages_have_no_layer() {
+        assert_eq!(layer(b"hello"), Ok(None));
+        assert_eq!(layer(b""), Ok(None));
+        assert_eq!(layer(&MAGIC), Ok(None));
+        assert!(decompress(b"hello", MAX_MESSAGE_LENGTH).is_err());
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape(b"hello".to_vec()), b"hello");
+        let compressed = compress(b"hello");
+        let escaped = escape(compressed.clone());
+        assert_eq!(layer(&escaped), Ok(Some(Layer::Escaped)));
+        assert_eq!(body(&escaped, Layer::Escaped), Ok(compressed.as_slice()));
+        // even when what follows the magic isn't a header this version knows
+        assert_eq!(
+            layer(&escape(b"\x89pme\x07".to_vec())),
+            Ok(Some(Layer::Escaped))
+        );
+    }
+
+    #[test]
+    fn test_unknown_layers() {
+        assert!(layer(&[0x89, b'p', b'm', b'e', 2, 1]).is_err());
+        assert!(layer(&[0x89, b'p', b'm', b'e', 1, 200]).is_err());
+        assert_eq!(
+            decompress(&wrap(Layer::Compressed, b"not zlib"), MAX_MESSAGE_LENGTH),
+            Err(PngError::InvalidPayload {
+                reason: "its compressed data is corrupt"
+            })
+        );
+    }
+
+    #[test]
+    fn test_layer_order() {
+        assert!(Layer::Split.can_contain(Layer::Recipients));
+        assert!(Layer::Split.can_contain(Layer::Compressed));
+        assert!(Layer::Passphrase.can_contain(Layer::Compressed));
+        assert!(!Layer::Compressed.can_contain(Layer::Compressed));
+        assert!(!Layer::Compressed.can_contain(Layer::Passphrase));
+        assert!(!Layer::Recipients.can_contain(Layer::Passphrase));
+        assert!(!Layer::Passphrase.can_contain(Layer::Split));
+        assert!(Layer::Compressed.can_contain(Layer::Escaped));
+        assert!(!Layer::Escaped.can_contain(Layer::Escaped));
+    }
+
+    #[test]
+    fn test_split_and_join() {
+        let message = b"This is where your secret message will be!";
+        let pieces = split(message, PIECE_HEADER_LENGTH + 10).unwrap();
+
+        assert_eq!(pieces.len(), 5);
+        assert!(pieces
+            .iter()
+            .all(|piece| piece.len() <= PIECE_HEADER_LENGTH + 10));
+        assert_eq!(layer(&pieces[0]), Ok(Some(Layer::Split)));
+        let third = piece(&pieces[2]).unwrap();
+        assert_eq!((third.sequence, third.count), (2, 5));
+        assert_eq!(third.data, b"ecret mess");
+
+        assert_eq!(join(pieces.iter().map(Vec::as_slice)).unwrap(), message);
+        assert_eq!(
+            join(pieces.iter().rev().map(Vec::as_slice)).unwrap(),
+            message
+        );
+        assert_eq!(split(message, 100).unwrap().len(), 1);
+        assert_eq!(join([split(b"", 100).unwrap()[0].as_slice()]).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_missing_and_duplicate_pieces() {
+        let pieces = split(&[7; 100], PIECE_HEADER_LENGTH + 25).unwrap();
+        let pick = |sequences: &[usize]| {
+            join(
+                sequences
+                    .iter()
+                    .map(|&sequence| pieces[sequence].as_slice()),
+            )
+        };
+
+        assert_eq!(
+            pick(&[0, 1, 3]),
+            Err(PngError::MissingPiece {
+                sequence: 2,
+                count: 4
+            })
+        );
+        assert_eq!(
+            pick(&[0, 1, 2]),
+            Err(PngError::MissingPiece {
+                sequence: 3,
+                count: 4
+            })
+        );
+        assert_eq!(
+            pick(&[0, 1, 2, 2, 3]),
+            Err(PngError::DuplicatePiece {
+                sequence: 2,
+                count: 4
+            })
+        );
+
+        // even the same message split again is a different message
+        for other in [&[8; 100], &[7; 100]] {
+            let other = split(other, PIECE_HEADER_LENGTH + 25).unwrap();
+            let mixed = [pieces[0].as_slice(), other[1].as_slice()];
+            assert!(matches!(join(mixed), Err(PngError::InvalidPayload { .. })));
+        }
+
+        let mut changed = pieces.clone();
+        *changed[1].last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            join(changed.iter().map(Vec::as_slice)),
+            Err(PngError::InvalidPayload { .. })
+        ));
+    }
+
+    #[test]
+    fn test_bad_pieces() {
+        assert!(split(b"hello", PIECE_HEADER_LENGTH).is_err());
+        assert!(piece(&wrap(Layer::Split, &[0; 19])).is_err());
+        // piece 3 of 3
+        let mut body = [0; 20];
+        body[11] = 3;
+        body[15] = 3;
+        assert!(piece(&wrap(Layer::Split, &body)).is_err());
+        assert!(join([]).is_err());
+    }
+}
@@ -1,37 +1,27 @@
-use crate::chunk::Chunk;
-use crate::chunk_type::ChunkType;
-use std::error::Error;
+use crate::chunk::{Chunk, ChunkRef};
+use crate::error::PngError;
+use crate::ihdr::Ihdr;
+use crate::stream::ChunkWriter;
+use crate::text::{TextualChunk, MAX_TEXT_LENGTH};
 use std::fmt::Display;
+use std::io::Write;
 
 pub struct Png {
     header: [u8; 8],
     chunks: Vec<Chunk>,
+    // whatever followed IEND that couldn't be read as chunks, kept so that it
+    // is written back out unchanged
+    trailing: Vec<u8>,
 }
 
 impl TryFrom<&[u8]> for Png {
-    type Error = &'static str;
+    type Error = PngError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        if Self::STANDARD_HEADER == value[..8] {
-            let mut value = &value[8..];
-            let mut data_exists = value.len() >= 12;
-            let mut chunks: Vec<Chunk> = Vec::new();
-            while data_exists {
-                // this is a huge mess -- it's unreadable, and it doesn't even
-                // handle errors reasonably.
-                let data_length = u32::from_be_bytes(value[..4].try_into().unwrap()) as usize; // woof
-                let chunk_type =
-                    ChunkType::try_from(TryInto::<[u8; 4]>::try_into(&value[4..8]).unwrap())?; // bad
-                chunks.push(Chunk::new(chunk_type, value[8..8 + data_length].to_vec())); // so bad
-                value = &value[8 + 4 + data_length..];
-                data_exists = value.len() >= 12;
-            }
-            Ok(Png {
-                header: Self::STANDARD_HEADER,
-                chunks,
-            })
-        } else {
-            Err("Value provided was not a PNG -- did not start with the correct standard header value")
+        let (png, crc_errors) = Png::parse_lenient(value)?;
+        match crc_errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(png),
         }
     }
 }
@@ -54,21 +44,152 @@ impl Png {
         Png {
             header: Self::STANDARD_HEADER,
             chunks,
+            trailing: Vec::new(),
         }
     }
 
+    // Like `try_from`, except that chunks whose stored CRC doesn't match their
+    // contents are kept (with the CRC they were stored with) instead of failing
+    // the parse. Every mismatch found is returned next to the PNG.
+    pub fn parse_lenient(value: &[u8]) -> Result<(Png, Vec<PngError>), PngError> {
+        let png = PngRef::try_from(value)?;
+        let mut chunks = Vec::new();
+        let mut crc_errors = Vec::new();
+        for chunk in png.chunks() {
+            if let Err(err) = chunk.check_crc() {
+                crc_errors.push(err);
+            }
+            chunks.push(chunk.to_chunk());
+        }
+
+        Ok((
+            Png {
+                header: Self::STANDARD_HEADER,
+                chunks,
+                trailing: png.trailing_bytes().to_vec(),
+            },
+            crc_errors,
+        ))
+    }
+
     pub fn append_chunk(&mut self, chunk: Chunk) {
         self.chunks.push(chunk);
     }
 
-    pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk, Box<dyn std::error::Error>> {
-        let chunks = self.chunks.clone();
-        chunks
+    // Inserts a chunk where the PNG spec says it may go: IHDR first, IEND last,
+    // PLTE before the image data, the colour space chunks (gAMA, iCCP and so
+    // on) before PLTE, bKGD/hIST/tRNS between PLTE and the image data, pHYs
+    // and friends before the image data, new IDATs after the existing ones, and
+    // everything else (text and custom chunks included) just before IEND.
+    pub fn insert_chunk(&mut self, chunk: Chunk) {
+        let index = self.insert_position(&chunk.chunk_type().bytes());
+        self.chunks.insert(index, chunk);
+    }
+
+    pub fn insert_at(&mut self, index: usize, chunk: Chunk) -> Result<(), PngError> {
+        if index <= self.chunks.len() {
+            self.chunks.insert(index, chunk);
+            Ok(())
+        } else {
+            Err(PngError::ChunkIndexOutOfRange {
+                index,
+                len: self.chunks.len(),
+            })
+        }
+    }
+
+    // Inserts a chunk just before the first chunk of the given type.
+    pub fn insert_before(&mut self, chunk_type: &str, chunk: Chunk) -> Result<(), PngError> {
+        let index = self
+            .chunk_indices(chunk_type)
+            .first()
+            .copied()
+            .ok_or_else(|| PngError::ChunkNotFound {
+                chunk_type: chunk_type.to_string(),
+            })?;
+        self.chunks.insert(index, chunk);
+        Ok(())
+    }
+
+    // Inserts a chunk just after the last chunk of the given type, so that
+    // runs of chunks like IDAT aren't split up.
+    pub fn insert_after(&mut self, chunk_type: &str, chunk: Chunk) -> Result<(), PngError> {
+        let index = self
+            .chunk_indices(chunk_type)
+            .last()
+            .copied()
+            .ok_or_else(|| PngError::ChunkNotFound {
+                chunk_type: chunk_type.to_string(),
+            })?;
+        self.chunks.insert(index + 1, chunk);
+        Ok(())
+    }
+
+    fn insert_position(&self, chunk_type: &[u8; 4]) -> usize {
+        // Each kind of chunk goes before the first of these that's present,
+        // falling back to before IEND (or the very end, without one).
+        let before: &[&[u8; 4]] = match chunk_type {
+            b"IHDR" => return 0,
+            b"IEND" => return self.chunks.len(),
+            b"IDAT" => {
+                if let Some(last) = self.positions(&[b"IDAT"]).last() {
+                    return last + 1;
+                }
+                &[]
+            }
+            b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" | b"cICP" | b"mDCv" | b"cLLi" => {
+                &[b"PLTE", b"IDAT"]
+            }
+            b"PLTE" => &[b"bKGD", b"hIST", b"tRNS", b"IDAT"],
+            b"bKGD" | b"hIST" | b"tRNS" | b"pHYs" | b"sPLT" | b"eXIf" | b"acTL" => &[b"IDAT"],
+            _ => &[],
+        };
+        self.positions(before)
+            .first()
+            .or(self.positions(&[b"IEND"]).first())
+            .copied()
+            .unwrap_or(self.chunks.len())
+    }
+
+    // The positions of every chunk whose type is one of `chunk_types`.
+    fn positions(&self, chunk_types: &[&[u8; 4]]) -> Vec<usize> {
+        self.chunks
             .iter()
             .enumerate()
-            .find(|&(_, c)| c.chunk_type().to_string() == chunk_type) // .find().map() seems wrong
-            .map(|(i, _)| self.chunks.remove(i))
-            .ok_or_else(|| Box::<dyn Error>::from("That chunk does not exist"))
+            .filter(|(_, c)| chunk_types.contains(&&c.chunk_type().bytes()))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk, PngError> {
+        self.chunks
+            .iter()
+            .position(|c| c.chunk_type().to_string() == chunk_type)
+            .map(|i| self.chunks.remove(i))
+            .ok_or_else(|| PngError::ChunkNotFound {
+                chunk_type: chunk_type.to_string(),
+            })
+    }
+
+    pub fn remove_chunk_at(&mut self, index: usize) -> Result<Chunk, PngError> {
+        if index < self.chunks.len() {
+            Ok(self.chunks.remove(index))
+        } else {
+            Err(PngError::ChunkIndexOutOfRange {
+                index,
+                len: self.chunks.len(),
+            })
+        }
+    }
+
+    // Removes every chunk of the given type, returning them in the order they
+    // appeared.
+    pub fn remove_chunks(&mut self, chunk_type: &str) -> Vec<Chunk> {
+        let (removed, kept) = std::mem::take(&mut self.chunks)
+            .into_iter()
+            .partition(|c| c.chunk_type().to_string() == chunk_type);
+        self.chunks = kept;
+        removed
     }
 
     pub fn header(&self) -> &[u8; 8] {
@@ -79,20 +200,431 @@ impl Png {
         &self.chunks
     }
 
+    pub fn trailing_bytes(&self) -> &[u8] {
+        &self.trailing
+    }
+
+    // The image header, which has to be the first chunk.
+    pub fn ihdr(&self) -> Result<Ihdr, PngError> {
+        match self.chunks.first() {
+            Some(chunk) if &chunk.chunk_type().bytes() == b"IHDR" => {
+                Ihdr::try_from(chunk.as_chunk_ref(Self::STANDARD_HEADER.len()))
+            }
+            _ => Err(PngError::MissingIhdr),
+        }
+    }
+
     pub fn chunk_by_type(&self, chunk_type: &str) -> Option<&Chunk> {
         self.chunks
             .iter()
             .find(|&c| c.chunk_type().to_string() == chunk_type)
     }
 
+    // Writes the PNG out without first building a copy of it in memory, which
+    // `as_bytes` has to do.
+    pub fn write_to<W: Write>(&self, writer: W) -> Result<(), PngError> {
+        let mut writer = ChunkWriter::new(writer)?;
+        for chunk in &self.chunks {
+            writer.write_chunk(chunk)?;
+        }
+        let mut writer = writer.into_inner();
+        writer.write_all(&self.trailing)?;
+        Ok(writer.flush()?)
+    }
+
+    pub fn chunks_by_type(&self, chunk_type: &str) -> Vec<&Chunk> {
+        self.chunks
+            .iter()
+            .filter(|c| c.chunk_type().to_string() == chunk_type)
+            .collect()
+    }
+
+    // The positions in `chunks()` of every chunk of the given type, for use
+    // with `remove_chunk_at`.
+    pub fn chunk_indices(&self, chunk_type: &str) -> Vec<usize> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.chunk_type().to_string() == chunk_type)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Every tEXt, zTXt and iTXt chunk, in order. Ones that can't be read are
+    // left in as errors, so that one bad chunk doesn't hide the rest.
+    pub fn text_chunks(&self) -> Vec<Result<TextualChunk, PngError>> {
+        self.text_chunks_with_max_length(MAX_TEXT_LENGTH)
+    }
+
+    // The same, letting compressed text inflate to up to `max_length` bytes.
+    pub fn text_chunks_with_max_length(
+        &self,
+        max_length: usize,
+    ) -> Vec<Result<TextualChunk, PngError>> {
+        self.chunk_refs()
+            .filter(|c| TextualChunk::is_textual(c.chunk_type()))
+            .map(|c| TextualChunk::with_max_length(c, max_length))
+            .collect()
+    }
+
+    pub fn text(&self, keyword: &str) -> Option<TextualChunk> {
+        self.text_chunks()
+            .into_iter()
+            .flatten()
+            .find(|text| text.keyword() == keyword)
+    }
+
+    // Replaces the text chunks with the same keyword as `text` (of any of the
+    // three kinds) with `text`, or adds it if there aren't any.
+    pub fn set_text(&mut self, text: TextualChunk) {
+        let indices = self.text_indices(text.keyword());
+        match indices.first() {
+            Some(&first) => {
+                for &index in indices.iter().rev() {
+                    self.chunks.remove(index);
+                }
+                self.chunks.insert(first, text.to_chunk());
+            }
+            None => self.insert_chunk(text.to_chunk()),
+        }
+    }
+
+    // Removes every text chunk with the given keyword, returning them.
+    pub fn remove_text(&mut self, keyword: &str) -> Vec<Chunk> {
+        let mut removed: Vec<Chunk> = self
+            .text_indices(keyword)
+            .iter()
+            .rev()
+            .map(|&index| self.chunks.remove(index))
+            .collect();
+        removed.reverse();
+        removed
+    }
+
+    // Goes by the keyword alone, so that a chunk whose text is too long (or
+    // otherwise unreadable) can still be replaced or removed.
+    fn text_indices(&self, keyword: &str) -> Vec<usize> {
+        self.chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| TextualChunk::keyword_of(c).is_some_and(|k| k == keyword))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    // Every chunk along with the offset it is at in `as_bytes()`.
+    fn chunk_refs(&self) -> impl Iterator<Item = ChunkRef<'_>> {
+        self.chunks
+            .iter()
+            .scan(Self::STANDARD_HEADER.len(), |offset, chunk| {
+                let chunk_ref = chunk.as_chunk_ref(*offset);
+                *offset += 12 + chunk.data().len();
+                Some(chunk_ref)
+            })
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         self.header
             .to_vec()
             .iter()
             .copied()
             .chain(self.chunks.iter().flat_map(|chunk| chunk.as_bytes()))
+            .chain(self.trailing.iter().copied())
             .collect()
     }
+
+    // Checks the PNG against the spec's rules about which chunks there must be,
+    // how many of each, and what order they come in, returning every problem
+    // found (so an empty list means the PNG is structurally sound). CRCs aren't
+    // looked at, since a `Png` can only hold bad ones via `parse_lenient`.
+    pub fn validate(&self) -> Vec<PngError> {
+        let mut problems = Vec::new();
+        let types: Vec<[u8; 4]> = self.chunks.iter().map(|c| c.chunk_type().bytes()).collect();
+        let offsets: Vec<usize> = self.chunk_refs().map(|c| c.offset()).collect();
+        let first = |chunk_type: &[u8; 4]| types.iter().position(|t| t == chunk_type);
+
+        if types.first() != Some(b"IHDR") {
+            problems.push(PngError::MissingIhdr);
+        }
+        if types.last() != Some(b"IEND") {
+            problems.push(PngError::MissingIend);
+        }
+        if first(b"IDAT").is_none() {
+            problems.push(PngError::ChunkNotFound {
+                chunk_type: "IDAT".to_string(),
+            });
+        }
+
+        let ihdr = self.ihdr();
+        match &ihdr {
+            // both of these are reported below with everything else
+            Err(PngError::MissingIhdr | PngError::InvalidChunkLength { .. }) | Ok(_) => {}
+            Err(err) => problems.push(err.clone()),
+        }
+        // Taken straight from the header's data, so the checks that depend on
+        // it still run when some other IHDR field is invalid.
+        let colour_type = match self.chunks.first() {
+            Some(chunk) if types[0] == *b"IHDR" && chunk.data().len() == Ihdr::LENGTH => {
+                Some(chunk.data()[9])
+            }
+            _ => None,
+        };
+        if colour_type == Some(3) && first(b"PLTE").is_none() {
+            problems.push(PngError::ChunkNotFound {
+                chunk_type: "PLTE".to_string(),
+            });
+        }
+
+        let last_idat = types.iter().rposition(|t| t == b"IDAT");
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let chunk_type = types[index];
+            let offset = offsets[index];
+            let length = chunk.data().len();
+
+            if !chunk.chunk_type().is_reserved_bit_valid() {
+                problems.push(PngError::ReservedBitSet { offset, chunk_type });
+            }
+            if chunk.chunk_type().is_critical() && !Self::is_known_critical(&chunk_type) {
+                problems.push(PngError::UnknownCriticalChunk { offset, chunk_type });
+            }
+            if Self::is_unique(&chunk_type) && first(&chunk_type) != Some(index) {
+                problems.push(PngError::DuplicateChunk { offset, chunk_type });
+            }
+            if !Self::is_valid_length(&chunk_type, length) {
+                problems.push(PngError::InvalidChunkLength {
+                    offset,
+                    chunk_type,
+                    length,
+                });
+            }
+
+            match (first(b"IDAT"), last_idat) {
+                (Some(start), Some(end))
+                    if index > start && index < end && &chunk_type != b"IDAT" =>
+                {
+                    problems.push(PngError::SplitImageData { offset, chunk_type })
+                }
+                _ => {}
+            }
+
+            let (before, after) = Self::ordering(&chunk_type);
+            for other in before {
+                if first(other).is_some_and(|position| position < index) {
+                    problems.push(PngError::ChunkOutOfOrder {
+                        offset,
+                        chunk_type,
+                        other: **other,
+                        before: true,
+                    });
+                }
+            }
+            for other in after {
+                if first(other).is_some_and(|position| position > index) {
+                    problems.push(PngError::ChunkOutOfOrder {
+                        offset,
+                        chunk_type,
+                        other: **other,
+                        before: false,
+                    });
+                }
+            }
+
+            // Greyscale images can't have a palette, and images with an alpha
+            // channel can't have a tRNS chunk. hIST only makes sense with PLTE.
+            let not_allowed = match (colour_type, &chunk_type) {
+                (Some(0 | 4), b"PLTE") | (Some(4 | 6), b"tRNS") => true,
+                (_, b"hIST") => first(b"PLTE").is_none(),
+                _ => false,
+            };
+            if not_allowed {
+                problems.push(PngError::ChunkNotAllowed {
+                    offset,
+                    chunk_type,
+                    colour_type: colour_type.unwrap_or_default(),
+                });
+            }
+        }
+
+        if !self.trailing.is_empty() {
+            let end = Self::STANDARD_HEADER.len()
+                + self
+                    .chunks
+                    .iter()
+                    .map(|c| 12 + c.data().len())
+                    .sum::<usize>();
+            problems.push(PngError::TrailingBytes {
+                offset: end,
+                count: self.trailing.len(),
+            });
+        }
+        problems
+    }
+
+    fn is_known_critical(chunk_type: &[u8; 4]) -> bool {
+        matches!(chunk_type, b"IHDR" | b"PLTE" | b"IDAT" | b"IEND")
+    }
+
+    // Chunk types that may appear at most once.
+    fn is_unique(chunk_type: &[u8; 4]) -> bool {
+        matches!(
+            chunk_type,
+            b"IHDR"
+                | b"PLTE"
+                | b"IEND"
+                | b"cHRM"
+                | b"gAMA"
+                | b"iCCP"
+                | b"sBIT"
+                | b"sRGB"
+                | b"cICP"
+                | b"mDCv"
+                | b"cLLi"
+                | b"bKGD"
+                | b"hIST"
+                | b"tRNS"
+                | b"pHYs"
+                | b"eXIf"
+                | b"acTL"
+                | b"tIME"
+        )
+    }
+
+    fn is_valid_length(chunk_type: &[u8; 4], length: usize) -> bool {
+        match chunk_type {
+            b"IHDR" => length == 13,
+            b"PLTE" => length > 0 && length <= 3 * 256 && length.is_multiple_of(3),
+            b"IEND" => length == 0,
+            _ => true,
+        }
+    }
+
+    // The chunk types a chunk of the given type has to come before, and the
+    // ones it has to come after (when they're present at all).
+    fn ordering(
+        chunk_type: &[u8; 4],
+    ) -> (&'static [&'static [u8; 4]], &'static [&'static [u8; 4]]) {
+        match chunk_type {
+            b"cHRM" | b"gAMA" | b"iCCP" | b"sBIT" | b"sRGB" | b"cICP" | b"mDCv" | b"cLLi" => {
+                (&[b"PLTE", b"IDAT"], &[])
+            }
+            b"PLTE" | b"pHYs" | b"sPLT" | b"eXIf" | b"acTL" => (&[b"IDAT"], &[]),
+            b"bKGD" | b"hIST" | b"tRNS" => (&[b"IDAT"], &[b"PLTE"]),
+            _ => (&[], &[]),
+        }
+    }
+}
+
+// A PNG that borrows its chunks from the buffer it was parsed from. Parsing
+// only walks the chunk headers to check they fit in the buffer; nothing is
+// copied and CRCs aren't checked until asked for.
+#[derive(Copy, Clone, Debug)]
+pub struct PngRef<'a> {
+    bytes: &'a [u8],
+    // where the last chunk ends; anything after it is trailing bytes
+    end: usize,
+}
+
+impl<'a> TryFrom<&'a [u8]> for PngRef<'a> {
+    type Error = PngError;
+
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        if !value.starts_with(&Png::STANDARD_HEADER) {
+            return Err(PngError::BadSignature {
+                found: value[..value.len().min(8)].to_vec(),
+            });
+        }
+
+        let mut offset = Png::STANDARD_HEADER.len();
+        let mut seen_iend = false;
+        while offset < value.len() {
+            match ChunkRef::read(value, offset) {
+                Ok(chunk) => {
+                    seen_iend |= &chunk.chunk_type().bytes() == b"IEND";
+                    offset += chunk.size();
+                }
+                // Plenty of tools tack junk onto the end of a finished PNG, and
+                // decoders ignore it, so it's kept as trailing bytes instead.
+                Err(_) if seen_iend => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(PngRef {
+            bytes: value,
+            end: offset,
+        })
+    }
+}
+
+impl Display for PngRef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.bytes {
+            write!(f, "{} ", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> PngRef<'a> {
+    pub fn header(&self) -> &'a [u8] {
+        &self.bytes[..Png::STANDARD_HEADER.len()]
+    }
+
+    pub fn chunks(&self) -> ChunkRefs<'a> {
+        ChunkRefs {
+            bytes: &self.bytes[..self.end],
+            offset: Png::STANDARD_HEADER.len(),
+        }
+    }
+
+    pub fn ihdr(&self) -> Result<Ihdr, PngError> {
+        match self.chunks().next() {
+            Some(chunk) if &chunk.chunk_type().bytes() == b"IHDR" => Ihdr::try_from(chunk),
+            _ => Err(PngError::MissingIhdr),
+        }
+    }
+
+    // Anything after IEND that isn't a chunk.
+    pub fn trailing_bytes(&self) -> &'a [u8] {
+        &self.bytes[self.end..]
+    }
+
+    pub fn chunk_by_type(&self, chunk_type: &str) -> Option<ChunkRef<'a>> {
+        self.chunks()
+            .find(|c| c.chunk_type().to_string() == chunk_type)
+    }
+
+    pub fn chunks_by_type<'b>(&self, chunk_type: &'b str) -> impl Iterator<Item = ChunkRef<'a>> + 'b
+    where
+        'a: 'b,
+    {
+        self.chunks()
+            .filter(move |c| c.chunk_type().to_string() == chunk_type)
+    }
+
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub fn to_png(&self) -> Result<Png, PngError> {
+        Png::try_from(self.bytes)
+    }
+}
+
+pub struct ChunkRefs<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for ChunkRefs<'a> {
+    type Item = ChunkRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // PngRef has already checked that every chunk fits, so this only stops
+        // at the end of the buffer.
+        let chunk = ChunkRef::read(self.bytes, self.offset).ok()?;
+        self.offset += chunk.size();
+        Some(chunk)
+    }
 }
 
 #[cfg(test)]
@@ -169,7 +701,7 @@ mod tests {
 
         let png = Png::try_from(bytes.as_ref());
 
-        assert!(png.is_err());
+        assert!(matches!(png, Err(PngError::BadSignature { .. })));
     }
 
     #[test]
@@ -194,6 +726,101 @@ mod tests {
         assert!(png.is_err());
     }
 
+    #[test]
+    fn test_truncated_file() {
+        let png = Png::try_from(&PNG_FILE[..PNG_FILE.len() - 6]);
+
+        assert_eq!(
+            png.err(),
+            Some(PngError::TruncatedChunk {
+                offset: PNG_FILE.len() - 12,
+                needed: 12,
+                available: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn test_every_truncation_is_an_error_not_a_panic() {
+        for len in 0..PNG_FILE.len() {
+            let _ = Png::try_from(&PNG_FILE[..len]);
+        }
+        assert!(Png::try_from(&PNG_FILE[..4]).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_crc() {
+        let mut bytes = PNG_FILE.to_vec();
+        // the first byte of IHDR's data
+        bytes[16] ^= 1;
+
+        let png = Png::try_from(bytes.as_ref());
+
+        assert!(matches!(
+            png,
+            Err(PngError::CrcMismatch {
+                offset: 29,
+                chunk_type: [73, 72, 68, 82],
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_crc_lenient() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[16] ^= 1;
+
+        let (png, crc_errors) = Png::parse_lenient(bytes.as_ref()).unwrap();
+
+        assert_eq!(crc_errors.len(), 1);
+        assert_eq!(crc_errors[0].offset(), Some(29));
+        assert!(!png.chunks()[0].is_crc_valid());
+        assert_eq!(png.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_png_ref() {
+        let png = PngRef::try_from(&PNG_FILE[..]).unwrap();
+        let owned = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        assert_eq!(png.chunks().count(), owned.chunks().len());
+        for (chunk, expected) in png.chunks().zip(owned.chunks()) {
+            assert!(chunk.to_chunk() == *expected);
+        }
+
+        let chunk = png.chunk_by_type("RuSt").unwrap();
+        assert_eq!(chunk.offset(), PNG_FILE.len() - 27);
+        assert_eq!(chunk.data_as_str().unwrap(), "hey");
+        assert!(png.chunk_by_type("TeSt").is_none());
+    }
+
+    #[test]
+    fn test_png_ref_display_matches_png() {
+        let png = PngRef::try_from(&PNG_FILE[..]).unwrap();
+        let owned = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        assert_eq!(png.to_string(), owned.to_string());
+    }
+
+    #[test]
+    fn test_png_ref_truncated() {
+        let png = PngRef::try_from(&PNG_FILE[..PNG_FILE.len() - 6]);
+
+        assert!(matches!(png, Err(PngError::TruncatedChunk { .. })));
+    }
+
+    #[test]
+    fn test_png_ref_does_not_check_crc() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[16] ^= 1;
+
+        let png = PngRef::try_from(bytes.as_ref()).unwrap();
+
+        assert!(!png.chunks().next().unwrap().is_crc_valid());
+        assert!(png.to_png().is_err());
+    }
+
     #[test]
     fn test_list_chunks() {
         let png = testing_png();
@@ -218,6 +845,92 @@ mod tests {
         assert_eq!(&chunk.data_as_string().unwrap(), "Message");
     }
 
+    #[test]
+    fn test_insert_chunk_before_iend() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.insert_chunk(chunk_from_strings("TeSt", "Message").unwrap());
+        let chunks = png.chunks();
+        assert_eq!(&chunks[0].chunk_type().to_string(), "IHDR");
+        assert_eq!(&chunks[chunks.len() - 2].chunk_type().to_string(), "TeSt");
+        assert_eq!(&chunks[chunks.len() - 1].chunk_type().to_string(), "IEND");
+    }
+
+    fn chunk_types(png: &Png) -> Vec<String> {
+        png.chunks()
+            .iter()
+            .map(|c| c.chunk_type().to_string())
+            .collect()
+    }
+
+    fn palette_png() -> Png {
+        Png::from_chunks(
+            ["IHDR", "PLTE", "IDAT", "IDAT", "IEND"]
+                .iter()
+                .map(|chunk_type| chunk_from_strings(chunk_type, "").unwrap())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_insert_chunk_in_spec_order() {
+        let mut png = palette_png();
+        for chunk_type in ["tEXt", "gAMA", "tRNS", "IDAT", "ruSt", "pHYs"] {
+            png.insert_chunk(chunk_from_strings(chunk_type, "").unwrap());
+        }
+
+        assert_eq!(
+            chunk_types(&png),
+            [
+                "IHDR", "gAMA", "PLTE", "tRNS", "pHYs", "IDAT", "IDAT", "IDAT", "tEXt", "ruSt",
+                "IEND"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_insert_plte_before_trns() {
+        let mut png = palette_png();
+        png.remove_chunk("PLTE").unwrap();
+        png.insert_chunk(chunk_from_strings("tRNS", "").unwrap());
+        png.insert_chunk(chunk_from_strings("PLTE", "").unwrap());
+        png.insert_chunk(chunk_from_strings("IHDR", "").unwrap());
+
+        assert_eq!(
+            chunk_types(&png),
+            ["IHDR", "IHDR", "PLTE", "tRNS", "IDAT", "IDAT", "IEND"]
+        );
+    }
+
+    #[test]
+    fn test_insert_at_before_after() {
+        let mut png = palette_png();
+        png.insert_at(1, chunk_from_strings("ruSa", "").unwrap())
+            .unwrap();
+        png.insert_before("IDAT", chunk_from_strings("ruSb", "").unwrap())
+            .unwrap();
+        png.insert_after("IDAT", chunk_from_strings("ruSc", "").unwrap())
+            .unwrap();
+
+        assert_eq!(
+            chunk_types(&png),
+            ["IHDR", "ruSa", "PLTE", "ruSb", "IDAT", "IDAT", "ruSc", "IEND"]
+        );
+        assert_eq!(
+            png.insert_at(9, chunk_from_strings("ruSd", "").unwrap()),
+            Err(PngError::ChunkIndexOutOfRange { index: 9, len: 8 })
+        );
+        assert!(png
+            .insert_after("tEXt", chunk_from_strings("ruSd", "").unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_insert_chunk_without_iend() {
+        let mut png = testing_png();
+        png.insert_chunk(chunk_from_strings("TeSt", "Message").unwrap());
+        assert_eq!(&png.chunks()[3].chunk_type().to_string(), "TeSt");
+    }
+
     #[test]
     fn test_remove_chunk() {
         let mut png = testing_png();
@@ -227,6 +940,349 @@ mod tests {
         assert!(chunk.is_none());
     }
 
+    #[test]
+    fn test_remove_missing_chunk() {
+        let mut png = testing_png();
+        assert_eq!(
+            png.remove_chunk("TeSt").err(),
+            Some(PngError::ChunkNotFound {
+                chunk_type: "TeSt".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_chunks_by_type() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("miDl", "I am a second middle chunk").unwrap());
+
+        let chunks = png.chunks_by_type("miDl");
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(&chunks[0].data_as_string().unwrap(), "I am another chunk");
+        assert_eq!(
+            &chunks[1].data_as_string().unwrap(),
+            "I am a second middle chunk"
+        );
+        assert!(png.chunks_by_type("TeSt").is_empty());
+        assert_eq!(png.chunk_indices("miDl"), vec![1, 3]);
+    }
+
+    #[test]
+    fn test_remove_chunk_at() {
+        let mut png = testing_png();
+        let chunk = png.remove_chunk_at(1).unwrap();
+        assert_eq!(&chunk.chunk_type().to_string(), "miDl");
+        assert_eq!(png.chunks().len(), 2);
+        assert_eq!(
+            png.remove_chunk_at(2).err(),
+            Some(PngError::ChunkIndexOutOfRange { index: 2, len: 2 })
+        );
+    }
+
+    #[test]
+    fn test_remove_chunks() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("miDl", "I am a second middle chunk").unwrap());
+
+        let removed = png.remove_chunks("miDl");
+
+        assert_eq!(removed.len(), 2);
+        assert_eq!(png.chunks().len(), 2);
+        assert!(png.chunk_by_type("miDl").is_none());
+        assert!(png.remove_chunks("miDl").is_empty());
+    }
+
+    #[test]
+    fn test_png_ref_chunks_by_type() {
+        let mut png = testing_png();
+        png.append_chunk(chunk_from_strings("miDl", "I am a second middle chunk").unwrap());
+        let bytes = png.as_bytes();
+        let png = PngRef::try_from(bytes.as_ref()).unwrap();
+
+        let offsets: Vec<usize> = png.chunks_by_type("miDl").map(|c| c.offset()).collect();
+
+        assert_eq!(offsets, vec![40, 101]);
+    }
+
+    // A minimal valid PNG of the given colour type, followed by the chunks
+    // of the given types (each with `data` as its data).
+    fn png_with_colour_type(colour_type: u8, chunk_types: &[&str], data: &[u8]) -> Png {
+        let chunk = |chunk_type: &str, data: &[u8]| {
+            Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+        };
+        let ihdr = [0, 0, 0, 1, 0, 0, 0, 1, 8, colour_type, 0, 0, 0];
+        let mut chunks = vec![chunk("IHDR", &ihdr)];
+        chunks.extend(chunk_types.iter().map(|chunk_type| chunk(chunk_type, data)));
+        Png::from_chunks(chunks)
+    }
+
+    #[test]
+    fn test_validate_valid_png() {
+        let mut png = png_with_colour_type(
+            3,
+            &["gAMA", "PLTE", "tRNS", "IDAT", "IDAT", "tEXt"],
+            &[0; 3],
+        );
+        png.append_chunk(chunk_from_strings("IEND", "").unwrap());
+
+        assert_eq!(png.validate(), []);
+    }
+
+    #[test]
+    fn test_validate_image_file() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        // the chunk the tests for this crate added is critical, which real
+        // PNG decoders would choke on
+        assert_eq!(
+            png.validate(),
+            [PngError::UnknownCriticalChunk {
+                offset: PNG_FILE.len() - 27,
+                chunk_type: *b"RuSt",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_missing_chunks() {
+        assert_eq!(
+            testing_png().validate(),
+            [
+                PngError::MissingIhdr,
+                PngError::MissingIend,
+                PngError::ChunkNotFound {
+                    chunk_type: "IDAT".to_string()
+                },
+                PngError::UnknownCriticalChunk {
+                    offset: 8,
+                    chunk_type: *b"FrSt",
+                },
+                PngError::UnknownCriticalChunk {
+                    offset: 70,
+                    chunk_type: *b"LASt",
+                },
+            ]
+        );
+
+        let png = png_with_colour_type(3, &["IDAT", "IEND"], &[]);
+        assert_eq!(
+            png.validate(),
+            [PngError::ChunkNotFound {
+                chunk_type: "PLTE".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_order_and_multiplicity() {
+        // IHDR is 25 bytes long, so the chunks (4 bytes of data each) start at
+        // 33, 49, 65 and so on
+        let png = png_with_colour_type(
+            2,
+            &["IDAT", "gAMA", "IDAT", "gAMA", "IEND", "IEND"],
+            &[0; 4],
+        );
+
+        assert_eq!(
+            png.validate(),
+            [
+                PngError::SplitImageData {
+                    offset: 49,
+                    chunk_type: *b"gAMA",
+                },
+                PngError::ChunkOutOfOrder {
+                    offset: 49,
+                    chunk_type: *b"gAMA",
+                    other: *b"IDAT",
+                    before: true,
+                },
+                PngError::DuplicateChunk {
+                    offset: 81,
+                    chunk_type: *b"gAMA",
+                },
+                PngError::ChunkOutOfOrder {
+                    offset: 81,
+                    chunk_type: *b"gAMA",
+                    other: *b"IDAT",
+                    before: true,
+                },
+                PngError::InvalidChunkLength {
+                    offset: 97,
+                    chunk_type: *b"IEND",
+                    length: 4,
+                },
+                PngError::DuplicateChunk {
+                    offset: 113,
+                    chunk_type: *b"IEND",
+                },
+                PngError::InvalidChunkLength {
+                    offset: 113,
+                    chunk_type: *b"IEND",
+                    length: 4,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_colour_type_rules() {
+        let png = png_with_colour_type(0, &["PLTE", "IDAT", "IEND"], &[]);
+        assert!(png.validate().contains(&PngError::ChunkNotAllowed {
+            offset: 33,
+            chunk_type: *b"PLTE",
+            colour_type: 0,
+        }));
+
+        let png = png_with_colour_type(6, &["tRNS", "IDAT", "IEND"], &[]);
+        assert!(png.validate().contains(&PngError::ChunkNotAllowed {
+            offset: 33,
+            chunk_type: *b"tRNS",
+            colour_type: 6,
+        }));
+
+        let png = png_with_colour_type(3, &["IDAT", "PLTE", "IEND"], &[0; 3]);
+        assert!(png.validate().contains(&PngError::ChunkOutOfOrder {
+            offset: 48,
+            chunk_type: *b"PLTE",
+            other: *b"IDAT",
+            before: true,
+        }));
+    }
+
+    #[test]
+    fn test_validate_bad_ihdr() {
+        let png = png_with_colour_type(5, &["IDAT", "IEND"], &[]);
+        assert_eq!(
+            png.validate(),
+            [PngError::InvalidIhdrField {
+                field: "colour type",
+                value: 5,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_validate_bad_ihdr_still_checks_palette() {
+        let mut png = png_with_colour_type(3, &["IDAT", "IEND"], &[]);
+        png.chunks[0] = Chunk::new(
+            ChunkType::from_str("IHDR").unwrap(),
+            vec![0, 0, 0, 0, 0, 0, 0, 1, 8, 3, 0, 0, 0],
+        );
+        assert_eq!(
+            png.validate(),
+            [
+                PngError::InvalidIhdrField {
+                    field: "width",
+                    value: 0,
+                },
+                PngError::ChunkNotFound {
+                    chunk_type: "PLTE".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ihdr() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let ihdr = png.ihdr().unwrap();
+
+        assert_eq!((ihdr.width(), ihdr.height()), (50, 50));
+        assert_eq!(ihdr.bit_depth(), 8);
+        assert_eq!(ihdr.colour_type(), crate::ihdr::ColourType::TruecolourAlpha);
+        assert_eq!(PngRef::try_from(&PNG_FILE[..]).unwrap().ihdr(), Ok(ihdr));
+        assert_eq!(testing_png().ihdr(), Err(PngError::MissingIhdr));
+    }
+
+    #[test]
+    fn test_validate_reserved_bit() {
+        let png = png_with_colour_type(2, &["IDAT", "ruse", "IEND"], &[]);
+        assert_eq!(
+            png.validate(),
+            [PngError::ReservedBitSet {
+                offset: 45,
+                chunk_type: *b"ruse",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes.extend_from_slice(b"junk");
+
+        let png = Png::try_from(bytes.as_ref()).unwrap();
+        let png_ref = PngRef::try_from(bytes.as_ref()).unwrap();
+
+        assert_eq!(png.trailing_bytes(), b"junk");
+        assert_eq!(png_ref.trailing_bytes(), b"junk");
+        assert_eq!(png_ref.chunks().count(), png.chunks().len());
+        assert_eq!(png.as_bytes(), bytes);
+        assert!(png.validate().contains(&PngError::TrailingBytes {
+            offset: PNG_FILE.len(),
+            count: 4,
+        }));
+    }
+
+    #[test]
+    fn test_junk_before_iend_is_an_error() {
+        let mut bytes = PNG_FILE[..PNG_FILE.len() - 12].to_vec();
+        bytes.extend_from_slice(b"junk");
+
+        assert!(PngRef::try_from(bytes.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_text_chunks() {
+        use crate::text::{CompressedTextChunk, TextChunk};
+
+        let mut png = palette_png();
+        png.set_text(TextualChunk::Text(TextChunk::new("Title", "Dice").unwrap()));
+        png.set_text(TextualChunk::Text(
+            TextChunk::new("Comment", "first").unwrap(),
+        ));
+        png.append_chunk(chunk_from_strings("tEXt", "no separator").unwrap());
+
+        let comment = CompressedTextChunk::new("Comment", "second").unwrap();
+        png.set_text(TextualChunk::Compressed(comment.clone()));
+
+        assert_eq!(
+            chunk_types(&png),
+            ["IHDR", "PLTE", "IDAT", "IDAT", "tEXt", "zTXt", "IEND", "tEXt"]
+        );
+        let texts = png.text_chunks();
+        assert_eq!(texts.len(), 3);
+        // the last chunk, and reported where it is in the file
+        let offset = png.as_bytes().len() - 12 - "no separator".len();
+        assert_eq!(texts[2].as_ref().unwrap_err().offset(), Some(offset));
+        assert_eq!(png.text("Comment"), Some(TextualChunk::Compressed(comment)));
+        assert_eq!(png.text("Title").unwrap().text(), "Dice");
+        assert_eq!(png.text("Author"), None);
+
+        assert_eq!(png.remove_text("Title").len(), 1);
+        assert!(png.remove_text("Title").is_empty());
+        assert_eq!(png.text_chunks().len(), 2);
+    }
+
+    #[test]
+    fn test_text_too_long_to_read() {
+        use crate::text::TextChunk;
+
+        let mut data = b"Big\0\0".to_vec();
+        data.extend(crate::zlib::compress(&vec![b'a'; MAX_TEXT_LENGTH + 1]));
+        let mut png = palette_png();
+        png.insert_chunk(Chunk::new(ChunkType::from_str("zTXt").unwrap(), data));
+        assert!(png.text_chunks()[0].is_err());
+
+        // still found by its keyword, so it is replaced rather than duplicated
+        png.set_text(TextualChunk::Text(TextChunk::new("Big", "small").unwrap()));
+        assert_eq!(png.text_chunks().len(), 1);
+        assert_eq!(png.text("Big").unwrap().text(), "small");
+
+        assert_eq!(png.remove_text("Big").len(), 1);
+        assert!(png.text_chunks().is_empty());
+    }
+
     #[test]
     fn test_png_from_image_file() {
         let png = Png::try_from(&PNG_FILE[..]);
@@ -241,6 +1297,26 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_write_to() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let mut actual = Vec::new();
+        png.write_to(&mut actual).unwrap();
+        assert_eq!(actual, PNG_FILE.to_vec());
+    }
+
+    #[test]
+    fn test_write_to_keeps_stored_crc() {
+        let mut bytes = PNG_FILE.to_vec();
+        bytes[16] ^= 1;
+        let (png, _) = Png::parse_lenient(bytes.as_ref()).unwrap();
+
+        let mut actual = Vec::new();
+        png.write_to(&mut actual).unwrap();
+        assert_eq!(actual, png.as_bytes());
+        assert_eq!(actual, bytes);
+    }
+
     #[test]
     fn test_png_trait_impls() {
         let chunk_bytes: Vec<u8> = testing_chunks()
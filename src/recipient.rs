@@ -0,0 +1,403 @@
+use crate::encrypt::{CIPHER_CHACHA20_POLY1305, NONCE_LENGTH};
+use crate::error::PngError;
+use crate::payload::{self, Layer};
+use base64::prelude::{Engine, BASE64_URL_SAFE_NO_PAD};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, OsRng, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::str::FromStr;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// The body of a recipients layer (see `payload`) is:
+//
+//   kind (1)            1 for X25519
+//   recipients (1)      how many stanzas follow, at least 1
+//   stanzas             one for each recipient:
+//     ephemeral (32)      a public key made for this stanza alone
+//     wrapped key (48)    the message key sealed for the recipient, with its tag
+//   cipher (1)          1 for ChaCha20-Poly1305
+//   nonce (12)
+//   ciphertext          with its 16 byte tag on the end
+//
+// This is how age works: the message is sealed once with a random key, and that
+// key is sealed again for each recipient with a key agreed between a fresh
+// ephemeral key pair and the recipient's public key. Nothing says who the
+// recipients are, so opening a message means trying each stanza in turn.
+// Everything before the ciphertext is authenticated along with it.
+const KIND_X25519: u8 = 1;
+pub(crate) const KEY_LENGTH: usize = 32;
+const WRAPPED_KEY_LENGTH: usize = KEY_LENGTH + 16;
+const STANZA_LENGTH: usize = KEY_LENGTH + WRAPPED_KEY_LENGTH;
+const WRAP_INFO: &[u8] = b"pngme x25519 v1";
+
+// How keys are written down, in key files and on the command line. The
+// prefixes say which half of a pair a key is, so a secret key can't be handed
+// out by mistake where a public one was meant.
+const PUBLIC_PREFIX: &str = "pngme-public-key-";
+const SECRET_PREFIX: &str = "pngme-secret-key-";
+
+// Someone a message can be encrypted to: an X25519 public key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+// The secret half of a key pair, which opens messages encrypted to its
+// recipient.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Recipient {
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        self.0.as_bytes()
+    }
+}
+
+impl From<[u8; KEY_LENGTH]> for Recipient {
+    fn from(bytes: [u8; KEY_LENGTH]) -> Self {
+        Recipient(PublicKey::from(bytes))
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = PngError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(SECRET_PREFIX) {
+            return Err(PngError::InvalidKey {
+                reason: "it is a secret key, where a public key was wanted",
+            });
+        }
+        key_bytes(s, PUBLIC_PREFIX).map(Recipient::from)
+    }
+}
+
+impl std::fmt::Display for Recipient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", key_string(self.as_bytes(), PUBLIC_PREFIX))
+    }
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Identity(StaticSecret::from(random_key()))
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    pub fn to_secret_string(&self) -> String {
+        key_string(self.0.as_bytes(), SECRET_PREFIX)
+    }
+}
+
+impl From<[u8; KEY_LENGTH]> for Identity {
+    fn from(bytes: [u8; KEY_LENGTH]) -> Self {
+        Identity(StaticSecret::from(bytes))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = PngError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(PUBLIC_PREFIX) {
+            return Err(PngError::InvalidKey {
+                reason: "it is a public key, where a secret key was wanted",
+            });
+        }
+        key_bytes(s, SECRET_PREFIX).map(Identity::from)
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        redacted_debug(f, "Identity", &self.recipient())
+    }
+}
+
+// Reads the keys in a key file: one per line, with blank lines and lines
+// starting with # skipped.
+pub fn parse_keys<T: FromStr<Err = PngError>>(text: &str) -> Result<Vec<T>, PngError> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(T::from_str)
+        .collect()
+}
+
+pub fn encrypt_to_recipients(
+    message: &[u8],
+    recipients: &[Recipient],
+) -> Result<Vec<u8>, PngError> {
+    let count = u8::try_from(recipients.len())
+        .ok()
+        .filter(|&count| count > 0)
+        .ok_or(PngError::InvalidPayload {
+            reason: "it must be encrypted to between 1 and 255 recipients",
+        })?;
+    let message_key = Key::from(random_key());
+    let mut nonce = [0; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+
+    let mut body = vec![KIND_X25519, count];
+    for recipient in recipients {
+        let ephemeral = StaticSecret::from(random_key());
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(&recipient.0);
+        if !shared.was_contributory() {
+            return Err(PngError::InvalidKey {
+                reason: "it is a weak key that nothing can be encrypted to",
+            });
+        }
+        let wrap_key = wrap_key(shared.as_bytes(), &ephemeral_public, &recipient.0);
+        let wrapped = ChaCha20Poly1305::new(&wrap_key)
+            .encrypt(&Nonce::default(), message_key.as_slice())
+            .expect("a key is never too long to encrypt");
+        body.extend_from_slice(ephemeral_public.as_bytes());
+        body.extend_from_slice(&wrapped);
+    }
+    body.push(CIPHER_CHACHA20_POLY1305);
+    body.extend_from_slice(&nonce);
+    let mut data = payload::wrap(Layer::Recipients, &body);
+
+    let ciphertext = ChaCha20Poly1305::new(&message_key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: message,
+                aad: &data,
+            },
+        )
+        .map_err(|_| PngError::InvalidPayload {
+            reason: "it is too long to encrypt",
+        })?;
+    data.extend_from_slice(&ciphertext);
+    Ok(data)
+}
+
+// Opens a message with whichever of `identities` it was encrypted to.
+pub fn decrypt_with_identities(data: &[u8], identities: &[Identity]) -> Result<Vec<u8>, PngError> {
+    let body = payload::body(data, Layer::Recipients)?;
+    let truncated = || PngError::InvalidPayload {
+        reason: "its encryption header is cut short",
+    };
+
+    if body.first() != Some(&KIND_X25519) {
+        return Err(PngError::InvalidPayload {
+            reason: "it was encrypted to a kind of key this version of pngme doesn't know",
+        });
+    }
+    let count = *body.get(1).ok_or_else(truncated)? as usize;
+    let stanzas = body
+        .get(2..2 + count * STANZA_LENGTH)
+        .ok_or_else(truncated)?;
+    let rest = &body[2 + stanzas.len()..];
+    if rest.first() != Some(&CIPHER_CHACHA20_POLY1305) {
+        return Err(PngError::InvalidPayload {
+            reason: "it was encrypted with a cipher this version of pngme doesn't know",
+        });
+    }
+    let nonce = rest.get(1..1 + NONCE_LENGTH).ok_or_else(truncated)?;
+    let ciphertext = &rest[1 + NONCE_LENGTH..];
+    let aad = &data[..data.len() - ciphertext.len()];
+
+    let message_key = identities
+        .iter()
+        .find_map(|identity| {
+            stanzas
+                .chunks_exact(STANZA_LENGTH)
+                .find_map(|stanza| unwrap_key(identity, stanza))
+        })
+        .ok_or(PngError::DecryptionFailed)?;
+    ChaCha20Poly1305::new(&message_key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| PngError::DecryptionFailed)
+}
+
+fn unwrap_key(identity: &Identity, stanza: &[u8]) -> Option<Key> {
+    let (ephemeral, wrapped) = stanza.split_at(KEY_LENGTH);
+    let ephemeral = PublicKey::from(<[u8; KEY_LENGTH]>::try_from(ephemeral).unwrap());
+    let shared = identity.0.diffie_hellman(&ephemeral);
+    if !shared.was_contributory() {
+        return None;
+    }
+    let wrap_key = wrap_key(shared.as_bytes(), &ephemeral, &identity.recipient().0);
+    let key = ChaCha20Poly1305::new(&wrap_key)
+        .decrypt(&Nonce::default(), wrapped)
+        .ok()?;
+    Some(Key::clone_from_slice(&key))
+}
+
+// Each wrap key is only ever used once, to seal one message key, so sealing
+// with an all-zero nonce is safe.
+fn wrap_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut salt = [0; 2 * KEY_LENGTH];
+    salt[..KEY_LENGTH].copy_from_slice(ephemeral.as_bytes());
+    salt[KEY_LENGTH..].copy_from_slice(recipient.as_bytes());
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .expect("32 bytes is a valid length for HKDF-SHA256");
+    key
+}
+
+pub(crate) fn random_key() -> [u8; KEY_LENGTH] {
+    let mut key = [0; KEY_LENGTH];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Every kind of pngme key is written as its prefix and then its 32 bytes in
+// base64: `key_string` writes one, and `key_bytes` reads it back.
+pub(crate) fn key_string(bytes: &[u8; KEY_LENGTH], prefix: &str) -> String {
+    format!("{}{}", prefix, BASE64_URL_SAFE_NO_PAD.encode(bytes))
+}
+
+pub(crate) fn key_bytes(s: &str, prefix: &str) -> Result<[u8; KEY_LENGTH], PngError> {
+    let encoded = s.strip_prefix(prefix).ok_or(PngError::InvalidKey {
+        reason: "it isn't the kind of pngme key wanted here",
+    })?;
+    let bytes = BASE64_URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|_| PngError::InvalidKey {
+            reason: "it isn't valid base64",
+        })?;
+    bytes.try_into().map_err(|_| PngError::InvalidKey {
+        reason: "it isn't 32 bytes long",
+    })
+}
+
+// The Debug output for the secret half of a key pair, which only shows the
+// public half so that a secret key never ends up in a log.
+pub(crate) fn redacted_debug(
+    f: &mut std::fmt::Formatter<'_>,
+    name: &str,
+    public: &impl std::fmt::Display,
+) -> std::fmt::Result {
+    write!(f, "{}({})", name, public)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let sealed =
+            encrypt_to_recipients(b"meet at dawn", &[alice.recipient(), bob.recipient()]).unwrap();
+
+        assert_eq!(payload::layer(&sealed), Ok(Some(Layer::Recipients)));
+        assert!(!sealed.windows(12).any(|w| w == b"meet at dawn"));
+        for identity in [&alice, &bob] {
+            assert_eq!(
+                decrypt_with_identities(&sealed, std::slice::from_ref(identity)).unwrap(),
+                b"meet at dawn"
+            );
+        }
+    }
+
+    #[test]
+    fn test_wrong_identity() {
+        let alice = Identity::generate();
+        let eve = Identity::generate();
+        let sealed = encrypt_to_recipients(b"meet at dawn", &[alice.recipient()]).unwrap();
+
+        assert_eq!(
+            decrypt_with_identities(&sealed, std::slice::from_ref(&eve)),
+            Err(PngError::DecryptionFailed)
+        );
+        assert_eq!(
+            decrypt_with_identities(&sealed, &[eve, alice]).unwrap(),
+            b"meet at dawn"
+        );
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let alice = Identity::generate();
+        let sealed = encrypt_to_recipients(b"meet at dawn", &[alice.recipient()]).unwrap();
+        // the last byte of the ciphertext, the ephemeral key, the wrapped key
+        // and the nonce
+        let stanza = payload::HEADER_LENGTH + 2;
+        for index in [
+            sealed.len() - 1,
+            stanza,
+            stanza + KEY_LENGTH,
+            stanza + STANZA_LENGTH + 1,
+        ] {
+            let mut tampered = sealed.clone();
+            tampered[index] ^= 1;
+            assert_eq!(
+                decrypt_with_identities(&tampered, std::slice::from_ref(&alice)),
+                Err(PngError::DecryptionFailed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_envelopes() {
+        let alice = Identity::generate();
+        let sealed = encrypt_to_recipients(b"meet at dawn", &[alice.recipient()]).unwrap();
+
+        for length in [payload::HEADER_LENGTH, payload::HEADER_LENGTH + 10, 90] {
+            assert!(matches!(
+                decrypt_with_identities(&sealed[..length], std::slice::from_ref(&alice)),
+                Err(PngError::InvalidPayload { .. })
+            ));
+        }
+        assert!(matches!(
+            encrypt_to_recipients(b"hi", &[]),
+            Err(PngError::InvalidPayload { .. })
+        ));
+        assert!(decrypt_with_identities(&payload::compress(b"hi"), &[alice]).is_err());
+    }
+
+    #[test]
+    fn test_weak_keys_are_refused() {
+        assert!(matches!(
+            encrypt_to_recipients(b"hi", &[Recipient::from([0; KEY_LENGTH])]),
+            Err(PngError::InvalidKey { .. })
+        ));
+    }
+
+    #[test]
+    fn test_key_strings() {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+
+        let public = recipient.to_string();
+        let secret = identity.to_secret_string();
+        assert!(public.starts_with("pngme-public-key-"));
+        assert!(secret.starts_with("pngme-secret-key-"));
+        assert_eq!(public.parse::<Recipient>(), Ok(recipient));
+        assert_eq!(secret.parse::<Identity>().unwrap().recipient(), recipient);
+        assert!(!format!("{:?}", identity).contains(&secret[17..]));
+
+        assert!(secret.parse::<Recipient>().is_err());
+        assert!(public.parse::<Identity>().is_err());
+        assert!("pngme-public-key-AAAA".parse::<Recipient>().is_err());
+        assert!("pngme-public-key-!!!!".parse::<Recipient>().is_err());
+        assert!("age1qqqq".parse::<Recipient>().is_err());
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let first = Identity::generate().recipient();
+        let second = Identity::generate().recipient();
+        let file = format!("# the team\n{first}\n\n  {second}  \n");
+
+        assert_eq!(parse_keys::<Recipient>(&file), Ok(vec![first, second]));
+        assert_eq!(parse_keys::<Recipient>("# nobody\n"), Ok(vec![]));
+        assert!(parse_keys::<Recipient>("not a key\n").is_err());
+    }
+}
@@ -0,0 +1,471 @@
+use crate::chunk::{Chunk, ChunkRef};
+use crate::chunk_type::ChunkType;
+use crate::error::PngError;
+use crate::png::PngRef;
+use crate::recipient::{key_bytes, key_string, random_key, redacted_debug, KEY_LENGTH};
+use ed25519_dalek::{Signer, SIGNATURE_LENGTH};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+
+// A signature is kept in a chunk of its own, which lists the chunks it covers
+// so that `verify` can say which of them have changed:
+//
+//   version (1)         1
+//   algorithm (1)       1 for Ed25519
+//   signer (32)         the public key to check the signature with
+//   count (4)           how many chunks are covered, big-endian
+//   covered             for each, in the order they were in the PNG:
+//     type (4)
+//     digest (32)         SHA-256 of the chunk's type and data
+//   signature (64)      of everything above, after DOMAIN
+//
+// The chunks covered are every critical chunk (which together make up the
+// image) and the chunks of the message's type. The signature chunk is unsafe
+// to copy, since it stops meaning anything once the image has been edited.
+pub const CHUNK_TYPE: &str = "siGN";
+const VERSION: u8 = 1;
+const ALGORITHM_ED25519: u8 = 1;
+const DIGEST_LENGTH: usize = 32;
+const HEADER_LENGTH: usize = 2 + KEY_LENGTH + 4;
+const ENTRY_LENGTH: usize = 4 + DIGEST_LENGTH;
+// Signed along with the chunk, so a signature can't be passed off as one made
+// for some other purpose with the same key.
+const DOMAIN: &[u8] = b"pngme signature v1\0";
+
+const SIGNING_PREFIX: &str = "pngme-signing-key-";
+const VERIFYING_PREFIX: &str = "pngme-verifying-key-";
+
+// The secret half of an Ed25519 key pair, which signs PNGs.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+// The public half, which checks signatures made with the signing key.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+impl SigningKey {
+    pub fn generate() -> Self {
+        SigningKey::from(random_key())
+    }
+
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.verifying_key())
+    }
+
+    pub fn to_secret_string(&self) -> String {
+        key_string(self.0.as_bytes(), SIGNING_PREFIX)
+    }
+}
+
+impl From<[u8; KEY_LENGTH]> for SigningKey {
+    fn from(bytes: [u8; KEY_LENGTH]) -> Self {
+        SigningKey(ed25519_dalek::SigningKey::from_bytes(&bytes))
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = PngError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(VERIFYING_PREFIX) {
+            return Err(PngError::InvalidKey {
+                reason: "it is a verifying key, where a signing key was wanted",
+            });
+        }
+        key_bytes(s, SIGNING_PREFIX).map(SigningKey::from)
+    }
+}
+
+impl std::fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        redacted_debug(f, "SigningKey", &self.verifying_key())
+    }
+}
+
+impl VerifyingKey {
+    pub fn as_bytes(&self) -> &[u8; KEY_LENGTH] {
+        self.0.as_bytes()
+    }
+}
+
+impl TryFrom<[u8; KEY_LENGTH]> for VerifyingKey {
+    type Error = PngError;
+
+    fn try_from(bytes: [u8; KEY_LENGTH]) -> Result<Self, Self::Error> {
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(VerifyingKey)
+            .map_err(|_| PngError::InvalidKey {
+                reason: "it isn't a point on the curve",
+            })
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = PngError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with(SIGNING_PREFIX) {
+            return Err(PngError::InvalidKey {
+                reason: "it is a signing key, where a verifying key was wanted",
+            });
+        }
+        VerifyingKey::try_from(key_bytes(s, VERIFYING_PREFIX)?)
+    }
+}
+
+impl std::fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", key_string(self.as_bytes(), VERIFYING_PREFIX))
+    }
+}
+
+// What `verify` found: who signed the PNG, and what has changed since.
+#[derive(Debug)]
+pub struct Verification {
+    pub signer: VerifyingKey,
+    pub problems: Vec<PngError>,
+}
+
+// Makes the signature chunk for `png`, covering its critical chunks and the
+// chunks of `message_type`. Any signature chunks already in `png` are left
+// out, so re-signing gives the same result as signing afresh.
+pub fn sign(png: &PngRef, message_type: &str, key: &SigningKey) -> Result<Chunk, PngError> {
+    let message_type = ChunkType::from_str(message_type)?;
+    let covered: Vec<ChunkRef> = png
+        .chunks()
+        .filter(|chunk| {
+            let chunk_type = chunk.chunk_type();
+            chunk_type.to_string() != CHUNK_TYPE
+                && (chunk_type.is_critical() || *chunk_type == message_type)
+        })
+        .collect();
+    if !covered
+        .iter()
+        .any(|chunk| *chunk.chunk_type() == message_type)
+    {
+        return Err(PngError::ChunkNotFound {
+            chunk_type: message_type.to_string(),
+        });
+    }
+
+    let mut data = vec![VERSION, ALGORITHM_ED25519];
+    data.extend_from_slice(key.verifying_key().as_bytes());
+    data.extend_from_slice(&(covered.len() as u32).to_be_bytes());
+    for chunk in &covered {
+        data.extend_from_slice(&chunk.chunk_type().bytes());
+        data.extend_from_slice(&digest(chunk));
+    }
+    let signature = key.0.sign(&[DOMAIN, &data].concat());
+    data.extend_from_slice(&signature.to_bytes());
+    Chunk::try_new(ChunkType::from_str(CHUNK_TYPE)?, data)
+}
+
+// Checks the first signature chunk in `png` against `keys`, then compares the
+// chunks it covers with the ones in `png` now. Everything that has been
+// changed, added or removed since signing is listed in the result's problems.
+pub fn verify(png: &PngRef, keys: &[VerifyingKey]) -> Result<Verification, PngError> {
+    let chunk = png
+        .chunk_by_type(CHUNK_TYPE)
+        .ok_or(PngError::ChunkNotFound {
+            chunk_type: CHUNK_TYPE.to_string(),
+        })?;
+    let malformed = |reason| PngError::MalformedChunk {
+        offset: chunk.offset(),
+        chunk_type: chunk.chunk_type().bytes(),
+        reason,
+    };
+    let data = chunk.data();
+    match data {
+        [VERSION, ALGORITHM_ED25519, ..] => {}
+        [VERSION, _, ..] => {
+            return Err(malformed(
+                "it uses a signature algorithm this version of pngme doesn't know",
+            ))
+        }
+        [_, ..] => return Err(malformed("it was written by a newer version of pngme")),
+        [] => return Err(malformed("it is empty")),
+    }
+    let header = data
+        .get(..HEADER_LENGTH)
+        .ok_or_else(|| malformed("it is cut short"))?;
+    let signer = VerifyingKey::try_from(<[u8; KEY_LENGTH]>::try_from(&header[2..34]).unwrap())?;
+    let count = u32::from_be_bytes(header[34..].try_into().unwrap()) as usize;
+    let signed_length = count
+        .checked_mul(ENTRY_LENGTH)
+        .and_then(|length| length.checked_add(HEADER_LENGTH))
+        .filter(|length| length + SIGNATURE_LENGTH == data.len())
+        .ok_or_else(|| malformed("its length doesn't match the number of chunks it lists"))?;
+    let (signed, signature) = data.split_at(signed_length);
+
+    if !keys.contains(&signer) {
+        return Err(PngError::UnknownSigner {
+            signer: signer.to_string(),
+        });
+    }
+    let signature = ed25519_dalek::Signature::from_bytes(signature.try_into().unwrap());
+    signer
+        .0
+        .verify_strict(&[DOMAIN, signed].concat(), &signature)
+        .map_err(|_| PngError::SignatureMismatch)?;
+
+    let before: Vec<([u8; 4], &[u8])> = signed[HEADER_LENGTH..]
+        .chunks_exact(ENTRY_LENGTH)
+        .map(|entry| (entry[..4].try_into().unwrap(), &entry[4..]))
+        .collect();
+    let now: Vec<([u8; 4], usize, [u8; DIGEST_LENGTH])> = png
+        .chunks()
+        .filter(|chunk| {
+            let chunk_type = chunk.chunk_type();
+            chunk_type.to_string() != CHUNK_TYPE
+                && (chunk_type.is_critical()
+                    || before.iter().any(|(t, _)| *t == chunk_type.bytes()))
+        })
+        .map(|chunk| (chunk.chunk_type().bytes(), chunk.offset(), digest(&chunk)))
+        .collect();
+
+    // Chunks are matched up by type and by position among chunks of that
+    // type, so that one chunk being added doesn't make all the ones after it
+    // look changed.
+    let mut types: Vec<[u8; 4]> = Vec::new();
+    for chunk_type in before
+        .iter()
+        .map(|(t, _)| *t)
+        .chain(now.iter().map(|(t, ..)| *t))
+    {
+        if !types.contains(&chunk_type) {
+            types.push(chunk_type);
+        }
+    }
+    let mut problems = Vec::new();
+    for chunk_type in types {
+        let before: Vec<&[u8]> = before
+            .iter()
+            .filter(|(t, _)| *t == chunk_type)
+            .map(|(_, digest)| *digest)
+            .collect();
+        let now: Vec<(usize, &[u8])> = now
+            .iter()
+            .filter(|(t, ..)| *t == chunk_type)
+            .map(|(_, offset, digest)| (*offset, &digest[..]))
+            .collect();
+        for index in 0..before.len().max(now.len()) {
+            match (before.get(index), now.get(index)) {
+                (Some(signed), Some(&(offset, digest))) if *signed != digest => {
+                    problems.push(PngError::ChunkChanged { offset, chunk_type })
+                }
+                (None, Some(&(offset, _))) => {
+                    problems.push(PngError::ChunkAdded { offset, chunk_type })
+                }
+                (Some(_), None) => problems.push(PngError::ChunkRemoved { chunk_type, index }),
+                _ => {}
+            }
+        }
+    }
+    let in_order = before
+        .iter()
+        .map(|(_, digest)| *digest)
+        .eq(now.iter().map(|(_, _, digest)| &digest[..]));
+    if problems.is_empty() && !in_order {
+        problems.push(PngError::ChunksReordered);
+    }
+
+    Ok(Verification { signer, problems })
+}
+
+fn digest(chunk: &ChunkRef) -> [u8; DIGEST_LENGTH] {
+    Sha256::new()
+        .chain_update(chunk.chunk_type().bytes())
+        .chain_update(chunk.data())
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::png::Png;
+
+    fn chunk(chunk_type: &str, data: &[u8]) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+    }
+
+    fn testing_png() -> Png {
+        Png::from_chunks(vec![
+            chunk("IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]),
+            chunk("tEXt", b"Comment\0not signed"),
+            chunk("IDAT", b"first"),
+            chunk("IDAT", b"second"),
+            chunk("ruSt", b"meet at dawn"),
+            chunk("IEND", b""),
+        ])
+    }
+
+    fn signed(png: &mut Png, key: &SigningKey) {
+        let bytes = png.as_bytes();
+        let signature = sign(&PngRef::try_from(&bytes[..]).unwrap(), "ruSt", key).unwrap();
+        png.insert_chunk(signature);
+    }
+
+    fn verified(png: &Png, key: &SigningKey) -> Result<Verification, PngError> {
+        let bytes = png.as_bytes();
+        verify(
+            &PngRef::try_from(&bytes[..]).unwrap(),
+            &[key.verifying_key()],
+        )
+    }
+
+    // Swaps the data of the chunk at `index` for `data`.
+    fn replaced(png: &Png, index: usize, data: &[u8]) -> Png {
+        let mut chunks = png.chunks().to_vec();
+        chunks[index] = chunk(&chunks[index].chunk_type().to_string(), data);
+        Png::from_chunks(chunks)
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let key = SigningKey::generate();
+        let mut png = testing_png();
+        signed(&mut png, &key);
+
+        let signature = png.chunk_by_type("siGN").unwrap();
+        assert!(!signature.chunk_type().is_critical());
+        assert!(!signature.chunk_type().is_safe_to_copy());
+        // IHDR, both IDATs, ruSt and IEND
+        assert_eq!(
+            signature.data().len(),
+            HEADER_LENGTH + 5 * ENTRY_LENGTH + SIGNATURE_LENGTH
+        );
+
+        let verification = verified(&png, &key).unwrap();
+        assert_eq!(verification.signer, key.verifying_key());
+        assert_eq!(verification.problems, vec![]);
+    }
+
+    #[test]
+    fn test_ancillary_chunks_can_change() {
+        let key = SigningKey::generate();
+        let mut png = testing_png();
+        signed(&mut png, &key);
+
+        let mut png = replaced(&png, 1, b"Comment\0edited");
+        png.insert_chunk(chunk("tIME", &[7, 234, 10, 18, 9, 0, 0]));
+        assert_eq!(verified(&png, &key).unwrap().problems, vec![]);
+    }
+
+    #[test]
+    fn test_tampered_chunks_are_reported() {
+        let key = SigningKey::generate();
+        let mut png = testing_png();
+        signed(&mut png, &key);
+        // offsets: IHDR 8, tEXt 33, IDAT 63 and 80, ruSt 98, IEND 122
+
+        let changed = replaced(&png, 3, b"SECOND");
+        assert_eq!(
+            verified(&changed, &key).unwrap().problems,
+            vec![PngError::ChunkChanged {
+                offset: 80,
+                chunk_type: *b"IDAT"
+            }]
+        );
+
+        let mut removed = changed;
+        removed.remove_chunk("ruSt").unwrap();
+        removed.insert_at(4, chunk("IDAT", b"third")).unwrap();
+        assert_eq!(
+            verified(&removed, &key).unwrap().problems,
+            vec![
+                PngError::ChunkChanged {
+                    offset: 80,
+                    chunk_type: *b"IDAT"
+                },
+                PngError::ChunkAdded {
+                    offset: 98,
+                    chunk_type: *b"IDAT"
+                },
+                PngError::ChunkRemoved {
+                    chunk_type: *b"ruSt",
+                    index: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reordering_is_reported() {
+        let key = SigningKey::generate();
+        let mut png = testing_png();
+        signed(&mut png, &key);
+
+        let message = png.remove_chunk("ruSt").unwrap();
+        png.insert_at(1, message).unwrap();
+        assert_eq!(
+            verified(&png, &key).unwrap().problems,
+            vec![PngError::ChunksReordered]
+        );
+    }
+
+    #[test]
+    fn test_bad_signatures() {
+        let key = SigningKey::generate();
+        let mut png = testing_png();
+        assert_eq!(
+            verified(&png, &key).unwrap_err(),
+            PngError::ChunkNotFound {
+                chunk_type: "siGN".to_string()
+            }
+        );
+
+        signed(&mut png, &key);
+        let other = SigningKey::generate();
+        assert!(matches!(
+            verified(&png, &other),
+            Err(PngError::UnknownSigner { .. })
+        ));
+
+        // flipping a bit of a listed digest, which the signature covers
+        let index = png.chunks().len() - 2;
+        let mut data = png.chunks()[index].data().to_vec();
+        data[HEADER_LENGTH + 10] ^= 1;
+        assert_eq!(
+            verified(&replaced(&png, index, &data), &key).unwrap_err(),
+            PngError::SignatureMismatch
+        );
+
+        data.pop();
+        assert!(matches!(
+            verified(&replaced(&png, index, &data), &key),
+            Err(PngError::MalformedChunk { .. })
+        ));
+    }
+
+    #[test]
+    fn test_sign_needs_the_message() {
+        let bytes = testing_png().as_bytes();
+        let png = PngRef::try_from(&bytes[..]).unwrap();
+        assert_eq!(
+            sign(&png, "miSs", &SigningKey::generate()).err(),
+            Some(PngError::ChunkNotFound {
+                chunk_type: "miSs".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_key_strings() {
+        let key = SigningKey::generate();
+        let public = key.verifying_key();
+
+        let secret = key.to_secret_string();
+        assert!(secret.starts_with("pngme-signing-key-"));
+        assert!(public.to_string().starts_with("pngme-verifying-key-"));
+        assert_eq!(
+            secret.parse::<SigningKey>().unwrap().verifying_key(),
+            public
+        );
+        assert_eq!(public.to_string().parse::<VerifyingKey>(), Ok(public));
+        assert!(!format!("{:?}", key).contains(&secret[18..]));
+
+        assert!(secret.parse::<VerifyingKey>().is_err());
+        assert!(public.to_string().parse::<SigningKey>().is_err());
+    }
+}
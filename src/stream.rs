@@ -0,0 +1,364 @@
+use crate::chunk::{Chunk, CRC};
+use crate::chunk_type::ChunkType;
+use crate::error::PngError;
+use crate::png::Png;
+use std::io::{Read, Write};
+
+// Reads a PNG one chunk at a time, so only the chunk currently being looked at
+// has to be held in memory. Iteration stops after the first error, or at the
+// first thing after IEND that isn't a chunk (which `PngRef` would treat as
+// trailing bytes).
+pub struct ChunkReader<R: Read> {
+    reader: R,
+    offset: usize,
+    seen_iend: bool,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(mut reader: R) -> Result<ChunkReader<R>, PngError> {
+        let mut header = [0; 8];
+        let read = read_up_to(&mut reader, &mut header)?;
+        if header[..read] != Png::STANDARD_HEADER {
+            return Err(PngError::BadSignature {
+                found: header[..read].to_vec(),
+            });
+        }
+
+        Ok(ChunkReader {
+            reader,
+            offset: Png::STANDARD_HEADER.len(),
+            seen_iend: false,
+            done: false,
+        })
+    }
+
+    // The byte offset of the next chunk to be read.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, PngError> {
+        let mut prefix = [0; 8];
+        let read = read_up_to(&mut self.reader, &mut prefix)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let truncated = |needed, available| PngError::TruncatedChunk {
+            offset: self.offset,
+            needed,
+            available,
+        };
+        if read < prefix.len() {
+            return Err(truncated(12, read));
+        }
+
+        let length = u32::from_be_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+        if length > Chunk::MAX_LENGTH {
+            return Err(PngError::LengthOverflow {
+                offset: self.offset,
+                length: length as usize,
+            });
+        }
+        let chunk_type = ChunkType::try_from([prefix[4], prefix[5], prefix[6], prefix[7]])
+            .map_err(|err| err.offset_by(self.offset + 4))?;
+
+        // `take` grows the buffer as data actually arrives, rather than trusting
+        // the length field with one big allocation up front.
+        let total = 12 + length as usize;
+        let mut data = Vec::new();
+        (&mut self.reader)
+            .take(length as u64)
+            .read_to_end(&mut data)?;
+        if data.len() < length as usize {
+            return Err(truncated(total, 8 + data.len()));
+        }
+
+        let mut crc = [0; 4];
+        let read = read_up_to(&mut self.reader, &mut crc)?;
+        if read < crc.len() {
+            return Err(truncated(total, total - 4 + read));
+        }
+
+        let chunk = Chunk::from_parts(chunk_type, data, u32::from_be_bytes(crc));
+        chunk.check_crc(self.offset + total - 4)?;
+        self.offset += total;
+        Ok(Some(chunk))
+    }
+}
+
+impl<R: Read> Iterator for ChunkReader<R> {
+    type Item = Result<Chunk, PngError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.read_chunk().transpose();
+        match &result {
+            Some(Ok(chunk)) => self.seen_iend |= &chunk.chunk_type().bytes() == b"IEND",
+            // a bad CRC still means a chunk was there to be read
+            Some(Err(err)) if self.seen_iend && !matches!(err, PngError::CrcMismatch { .. }) => {
+                self.done = true;
+                return None;
+            }
+            _ => self.done = true,
+        }
+        result
+    }
+}
+
+// Writes a PNG one chunk at a time. The signature is written straight away,
+// and the CRC of each chunk written from its data is worked out as the data
+// goes past.
+pub struct ChunkWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> ChunkWriter<W> {
+    pub fn new(mut writer: W) -> Result<ChunkWriter<W>, PngError> {
+        writer.write_all(&Png::STANDARD_HEADER)?;
+        Ok(ChunkWriter { writer })
+    }
+
+    // Writes a chunk as it is, with the CRC it was stored with even if that
+    // doesn't match its data, just as `Chunk::as_bytes` does.
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<(), PngError> {
+        self.writer.write_all(&chunk.length().to_be_bytes())?;
+        self.writer.write_all(&chunk.chunk_type().bytes())?;
+        self.writer.write_all(chunk.data())?;
+        Ok(self.writer.write_all(&chunk.crc().to_be_bytes())?)
+    }
+
+    pub fn write_data(&mut self, chunk_type: ChunkType, data: &[u8]) -> Result<(), PngError> {
+        let length = u32::try_from(data.len()).unwrap_or(u32::MAX);
+        let mut chunk = self.start_chunk(chunk_type, length)?;
+        chunk.write_all(data)?;
+        chunk.finish()
+    }
+
+    // Starts a chunk whose data will be written through the returned writer,
+    // for when the data is too big (or arrives too slowly) to gather up first.
+    // Exactly `length` bytes have to be written before calling `finish`.
+    pub fn start_chunk(
+        &mut self,
+        chunk_type: ChunkType,
+        length: u32,
+    ) -> Result<ChunkDataWriter<'_, W>, PngError> {
+        if length > Chunk::MAX_LENGTH {
+            return Err(PngError::LengthOverflow {
+                offset: 0,
+                length: length as usize,
+            });
+        }
+        self.writer.write_all(&length.to_be_bytes())?;
+        self.writer.write_all(&chunk_type.bytes())?;
+
+        let mut digest = CRC.digest();
+        digest.update(&chunk_type.bytes());
+        Ok(ChunkDataWriter {
+            writer: &mut self.writer,
+            digest,
+            remaining: length as usize,
+        })
+    }
+
+    pub fn flush(&mut self) -> Result<(), PngError> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+pub struct ChunkDataWriter<'a, W: Write> {
+    writer: &'a mut W,
+    digest: crc::Digest<'static, u32>,
+    remaining: usize,
+}
+
+impl<W: Write> ChunkDataWriter<'_, W> {
+    pub fn finish(self) -> Result<(), PngError> {
+        if self.remaining != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "chunk finished {} bytes short of its length",
+                    self.remaining
+                ),
+            )
+            .into());
+        }
+        self.writer
+            .write_all(&self.digest.finalize().to_be_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ChunkDataWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.len() > self.remaining {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "wrote more data than the chunk's length",
+            ));
+        }
+        let written = self.writer.write(buf)?;
+        self.digest.update(&buf[..written]);
+        self.remaining -= written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// `read_exact`, except running out of input early isn't an error: the number
+// of bytes actually read is returned instead.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn testing_png() -> Png {
+        Png::from_chunks(vec![
+            Chunk::new(
+                ChunkType::from_str("FrSt").unwrap(),
+                b"I am the first chunk".to_vec(),
+            ),
+            Chunk::new(ChunkType::from_str("miDl").unwrap(), Vec::new()),
+            Chunk::new(
+                ChunkType::from_str("LASt").unwrap(),
+                b"I am the last chunk".to_vec(),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_read_chunks() {
+        let png = testing_png();
+        let bytes = png.as_bytes();
+
+        let chunks = ChunkReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<Chunk>, PngError>>()
+            .unwrap();
+
+        assert!(chunks == png.chunks());
+    }
+
+    #[test]
+    fn test_read_bad_signature() {
+        let bytes = [137, 80, 78, 71];
+        assert!(matches!(
+            ChunkReader::new(bytes.as_slice()),
+            Err(PngError::BadSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let bytes = testing_png().as_bytes();
+        let mut reader = ChunkReader::new(&bytes[..bytes.len() - 2]).unwrap();
+
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_ok());
+        assert_eq!(
+            reader.next().unwrap().err(),
+            Some(PngError::TruncatedChunk {
+                offset: 52,
+                needed: 31,
+                available: 29,
+            })
+        );
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_read_bad_crc() {
+        let mut bytes = testing_png().as_bytes();
+        bytes[20] ^= 1;
+        let mut reader = ChunkReader::new(bytes.as_slice()).unwrap();
+
+        assert!(matches!(
+            reader.next(),
+            Some(Err(PngError::CrcMismatch { offset: 36, .. }))
+        ));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_read_stops_at_trailing_bytes() {
+        let mut png = testing_png();
+        png.append_chunk(Chunk::new(ChunkType::from_str("IEND").unwrap(), Vec::new()));
+        let mut bytes = png.as_bytes();
+        bytes.extend_from_slice(b"junk");
+
+        let chunks = ChunkReader::new(bytes.as_slice())
+            .unwrap()
+            .collect::<Result<Vec<Chunk>, PngError>>()
+            .unwrap();
+
+        assert!(chunks == png.chunks());
+    }
+
+    #[test]
+    fn test_write_chunks() {
+        let png = testing_png();
+
+        let mut writer = ChunkWriter::new(Vec::new()).unwrap();
+        for chunk in png.chunks() {
+            writer.write_chunk(chunk).unwrap();
+        }
+
+        assert_eq!(writer.into_inner(), png.as_bytes());
+    }
+
+    #[test]
+    fn test_write_chunk_in_pieces() {
+        let chunk_type = ChunkType::from_str("RuSt").unwrap();
+        let data = b"This is where your secret message will be!";
+
+        let mut writer = ChunkWriter::new(Vec::new()).unwrap();
+        let mut chunk = writer.start_chunk(chunk_type, data.len() as u32).unwrap();
+        for piece in data.chunks(5) {
+            chunk.write_all(piece).unwrap();
+        }
+        chunk.finish().unwrap();
+
+        let expected = Png::from_chunks(vec![Chunk::new(chunk_type, data.to_vec())]);
+        assert_eq!(writer.into_inner(), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_write_chunk_wrong_length() {
+        let chunk_type = ChunkType::from_str("RuSt").unwrap();
+        let mut writer = ChunkWriter::new(Vec::new()).unwrap();
+
+        let mut chunk = writer.start_chunk(chunk_type, 4).unwrap();
+        assert!(chunk.write_all(b"too long").is_err());
+
+        let mut chunk = writer.start_chunk(chunk_type, 4).unwrap();
+        chunk.write_all(b"ab").unwrap();
+        assert!(chunk.finish().is_err());
+    }
+}
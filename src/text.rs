@@ -0,0 +1,578 @@
+use crate::chunk::{Chunk, ChunkRef};
+use crate::chunk_type::ChunkType;
+use crate::error::PngError;
+use crate::zlib::{compress, decompress_at_most};
+use std::str::FromStr;
+
+// The longest a keyword can be, in bytes (which for Latin-1 is characters).
+pub const MAX_KEYWORD_LENGTH: usize = 79;
+
+// How long compressed text may inflate to, unless more is asked for: far more
+// than any real text needs, while keeping a small chunk from using up
+// gigabytes of memory.
+pub const MAX_TEXT_LENGTH: usize = 16 << 20;
+
+// A tEXt chunk: a keyword and some uncompressed Latin-1 text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextChunk {
+    keyword: String,
+    text: String,
+}
+
+impl TryFrom<&Chunk> for TextChunk {
+    type Error = PngError;
+
+    fn try_from(value: &Chunk) -> Result<Self, Self::Error> {
+        TextChunk::from_data(value.as_chunk_ref(0))
+    }
+}
+
+impl TextChunk {
+    pub fn new(keyword: &str, text: &str) -> Result<TextChunk, PngError> {
+        check_keyword(keyword)?;
+        to_latin1(text, "text")?;
+        Ok(TextChunk {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+        })
+    }
+
+    fn from_data(chunk: ChunkRef) -> Result<TextChunk, PngError> {
+        expect_type(&chunk, b"tEXt")?;
+        let (keyword, text) = split_keyword(&chunk, chunk.data())?;
+        TextChunk::new(&keyword, &from_latin1(text))
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = to_latin1(&self.keyword, "keyword").unwrap();
+        data.push(0);
+        data.extend(to_latin1(&self.text, "text").unwrap());
+        Chunk::new(ChunkType::from_str("tEXt").unwrap(), data)
+    }
+}
+
+// A zTXt chunk: the same as tEXt, except that the text is zlib-compressed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompressedTextChunk {
+    keyword: String,
+    text: String,
+}
+
+impl TryFrom<&Chunk> for CompressedTextChunk {
+    type Error = PngError;
+
+    fn try_from(value: &Chunk) -> Result<Self, Self::Error> {
+        CompressedTextChunk::from_data(value.as_chunk_ref(0), MAX_TEXT_LENGTH)
+    }
+}
+
+impl CompressedTextChunk {
+    pub fn new(keyword: &str, text: &str) -> Result<CompressedTextChunk, PngError> {
+        check_keyword(keyword)?;
+        to_latin1(text, "text")?;
+        Ok(CompressedTextChunk {
+            keyword: keyword.to_string(),
+            text: text.to_string(),
+        })
+    }
+
+    fn from_data(chunk: ChunkRef, max_length: usize) -> Result<CompressedTextChunk, PngError> {
+        expect_type(&chunk, b"zTXt")?;
+        let (keyword, rest) = split_keyword(&chunk, chunk.data())?;
+        let text = match rest.split_first() {
+            Some((0, compressed)) => decompress_at_most(compressed, max_length)
+                .map_err(|reason| malformed(&chunk, reason))?,
+            Some(_) => return Err(malformed(&chunk, "it uses an unknown compression method")),
+            None => return Err(malformed(&chunk, "it has no compression method")),
+        };
+        CompressedTextChunk::new(&keyword, &from_latin1(&text))
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = to_latin1(&self.keyword, "keyword").unwrap();
+        data.extend([0, 0]);
+        data.extend(compress(&to_latin1(&self.text, "text").unwrap()));
+        Chunk::new(ChunkType::from_str("zTXt").unwrap(), data)
+    }
+}
+
+// An iTXt chunk: UTF-8 text, optionally compressed, along with the language
+// it's in and the keyword translated into that language (both of which may be
+// empty).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InternationalTextChunk {
+    keyword: String,
+    compressed: bool,
+    language_tag: String,
+    translated_keyword: String,
+    text: String,
+}
+
+impl TryFrom<&Chunk> for InternationalTextChunk {
+    type Error = PngError;
+
+    fn try_from(value: &Chunk) -> Result<Self, Self::Error> {
+        InternationalTextChunk::from_data(value.as_chunk_ref(0), MAX_TEXT_LENGTH)
+    }
+}
+
+impl InternationalTextChunk {
+    pub fn new(
+        keyword: &str,
+        language_tag: &str,
+        translated_keyword: &str,
+        text: &str,
+        compressed: bool,
+    ) -> Result<InternationalTextChunk, PngError> {
+        check_keyword(keyword)?;
+        // Language tags (RFC 3066) are made of ASCII letters and digits,
+        // split up by hyphens.
+        if !language_tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        {
+            return Err(PngError::InvalidTextField {
+                field: "language tag",
+                reason: "may only contain ASCII letters, digits and hyphens",
+            });
+        }
+        if translated_keyword.contains('\0') {
+            return Err(PngError::InvalidTextField {
+                field: "translated keyword",
+                reason: "may not contain a null character",
+            });
+        }
+
+        Ok(InternationalTextChunk {
+            keyword: keyword.to_string(),
+            compressed,
+            language_tag: language_tag.to_string(),
+            translated_keyword: translated_keyword.to_string(),
+            text: text.to_string(),
+        })
+    }
+
+    fn from_data(chunk: ChunkRef, max_length: usize) -> Result<InternationalTextChunk, PngError> {
+        expect_type(&chunk, b"iTXt")?;
+        let (keyword, rest) = split_keyword(&chunk, chunk.data())?;
+        let (compressed, rest) = match rest {
+            [0, _, rest @ ..] => (false, rest),
+            [1, 0, rest @ ..] => (true, rest),
+            [1, _, ..] => return Err(malformed(&chunk, "it uses an unknown compression method")),
+            _ => {
+                return Err(malformed(
+                    &chunk,
+                    "its compression flag is missing or invalid",
+                ))
+            }
+        };
+
+        let mut fields = rest.splitn(3, |&b| b == 0);
+        let (Some(language_tag), Some(translated_keyword), Some(text)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            return Err(malformed(&chunk, "it is missing a null separator"));
+        };
+        let text = if compressed {
+            decompress_at_most(text, max_length).map_err(|reason| malformed(&chunk, reason))?
+        } else {
+            text.to_vec()
+        };
+        let utf8 = |bytes| {
+            std::str::from_utf8(bytes).map_err(|_| malformed(&chunk, "its text is not valid UTF-8"))
+        };
+
+        InternationalTextChunk::new(
+            &keyword,
+            utf8(language_tag)?,
+            utf8(translated_keyword)?,
+            utf8(&text)?,
+            compressed,
+        )
+    }
+
+    pub fn keyword(&self) -> &str {
+        &self.keyword
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    pub fn language_tag(&self) -> &str {
+        &self.language_tag
+    }
+
+    pub fn translated_keyword(&self) -> &str {
+        &self.translated_keyword
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut data = to_latin1(&self.keyword, "keyword").unwrap();
+        data.extend([0, self.compressed as u8, 0]);
+        data.extend(self.language_tag.as_bytes());
+        data.push(0);
+        data.extend(self.translated_keyword.as_bytes());
+        data.push(0);
+        if self.compressed {
+            data.extend(compress(self.text.as_bytes()));
+        } else {
+            data.extend(self.text.as_bytes());
+        }
+        Chunk::new(ChunkType::from_str("iTXt").unwrap(), data)
+    }
+}
+
+// Any of the three kinds of text chunk, for when it doesn't matter which.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TextualChunk {
+    Text(TextChunk),
+    Compressed(CompressedTextChunk),
+    International(InternationalTextChunk),
+}
+
+// A chunk on its own doesn't know where it sits in a file, so errors give its
+// offset as 0. `Png` reads its text chunks through `ChunkRef`s instead.
+impl TryFrom<&Chunk> for TextualChunk {
+    type Error = PngError;
+
+    fn try_from(value: &Chunk) -> Result<Self, Self::Error> {
+        TextualChunk::try_from(value.as_chunk_ref(0))
+    }
+}
+
+impl TryFrom<ChunkRef<'_>> for TextualChunk {
+    type Error = PngError;
+
+    fn try_from(value: ChunkRef<'_>) -> Result<Self, Self::Error> {
+        TextualChunk::with_max_length(value, MAX_TEXT_LENGTH)
+    }
+}
+
+impl TextualChunk {
+    // Reads a text chunk whose text, if compressed, may inflate to up to
+    // `max_length` bytes rather than `MAX_TEXT_LENGTH`.
+    pub fn with_max_length(chunk: ChunkRef, max_length: usize) -> Result<Self, PngError> {
+        match &chunk.chunk_type().bytes() {
+            b"tEXt" => TextChunk::from_data(chunk).map(TextualChunk::Text),
+            b"zTXt" => {
+                CompressedTextChunk::from_data(chunk, max_length).map(TextualChunk::Compressed)
+            }
+            b"iTXt" => InternationalTextChunk::from_data(chunk, max_length)
+                .map(TextualChunk::International),
+            found => Err(PngError::UnexpectedChunkType {
+                offset: chunk.offset(),
+                expected: *b"tEXt",
+                found: *found,
+            }),
+        }
+    }
+
+    pub fn is_textual(chunk_type: &ChunkType) -> bool {
+        matches!(&chunk_type.bytes(), b"tEXt" | b"zTXt" | b"iTXt")
+    }
+
+    // The keyword of a text chunk of any of the three kinds, read without
+    // looking at (or inflating) the rest of it.
+    pub fn keyword_of(chunk: &Chunk) -> Option<String> {
+        if !Self::is_textual(chunk.chunk_type()) {
+            return None;
+        }
+        let end = chunk.data().iter().position(|&b| b == 0)?;
+        Some(from_latin1(&chunk.data()[..end]))
+    }
+
+    // Which of the three it is, without the work of building the chunk.
+    pub fn chunk_type(&self) -> ChunkType {
+        let chunk_type = match self {
+            TextualChunk::Text(_) => "tEXt",
+            TextualChunk::Compressed(_) => "zTXt",
+            TextualChunk::International(_) => "iTXt",
+        };
+        ChunkType::from_str(chunk_type).unwrap()
+    }
+
+    pub fn keyword(&self) -> &str {
+        match self {
+            TextualChunk::Text(chunk) => chunk.keyword(),
+            TextualChunk::Compressed(chunk) => chunk.keyword(),
+            TextualChunk::International(chunk) => chunk.keyword(),
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        match self {
+            TextualChunk::Text(chunk) => chunk.text(),
+            TextualChunk::Compressed(chunk) => chunk.text(),
+            TextualChunk::International(chunk) => chunk.text(),
+        }
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        match self {
+            TextualChunk::Text(chunk) => chunk.to_chunk(),
+            TextualChunk::Compressed(chunk) => chunk.to_chunk(),
+            TextualChunk::International(chunk) => chunk.to_chunk(),
+        }
+    }
+}
+
+// Keywords are 1 to 79 printable Latin-1 characters (spaces included, but not
+// at either end or two in a row).
+fn check_keyword(keyword: &str) -> Result<(), PngError> {
+    let invalid = || PngError::InvalidKeyword {
+        keyword: keyword.to_string(),
+    };
+    let printable = |c: char| matches!(c, ' '..='~' | '\u{a1}'..='\u{ff}');
+
+    if keyword.chars().count() > MAX_KEYWORD_LENGTH
+        || keyword.is_empty()
+        || !keyword.chars().all(printable)
+        || keyword.starts_with(' ')
+        || keyword.ends_with(' ')
+        || keyword.contains("  ")
+    {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+fn to_latin1(text: &str, field: &'static str) -> Result<Vec<u8>, PngError> {
+    text.chars()
+        .map(|c| match c {
+            '\0' => Err(PngError::InvalidTextField {
+                field,
+                reason: "may not contain a null character",
+            }),
+            c if (c as u32) <= 0xff => Ok(c as u8),
+            _ => Err(PngError::InvalidTextField {
+                field,
+                reason: "can only contain Latin-1 characters",
+            }),
+        })
+        .collect()
+}
+
+// Latin-1 bytes map straight onto the same code points.
+fn from_latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn expect_type(chunk: &ChunkRef, expected: &[u8; 4]) -> Result<(), PngError> {
+    if &chunk.chunk_type().bytes() == expected {
+        Ok(())
+    } else {
+        Err(PngError::UnexpectedChunkType {
+            offset: chunk.offset(),
+            expected: *expected,
+            found: chunk.chunk_type().bytes(),
+        })
+    }
+}
+
+// Splits off the null-terminated keyword every text chunk starts with.
+fn split_keyword<'a>(chunk: &ChunkRef, data: &'a [u8]) -> Result<(String, &'a [u8]), PngError> {
+    match data.iter().position(|&b| b == 0) {
+        Some(end) => Ok((from_latin1(&data[..end]), &data[end + 1..])),
+        None => Err(malformed(chunk, "it has no null after its keyword")),
+    }
+}
+
+fn malformed(chunk: &ChunkRef, reason: &'static str) -> PngError {
+    PngError::MalformedChunk {
+        offset: chunk.offset(),
+        chunk_type: chunk.chunk_type().bytes(),
+        reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(chunk_type: &str, data: &[u8]) -> Chunk {
+        Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+    }
+
+    #[test]
+    fn test_text_chunk() {
+        let text = TextChunk::new("Comment", "caf\u{e9}").unwrap();
+        let chunk = text.to_chunk();
+
+        assert_eq!(chunk.data(), b"Comment\0caf\xe9");
+        assert_eq!(TextChunk::try_from(&chunk).unwrap(), text);
+    }
+
+    #[test]
+    fn test_compressed_text_chunk() {
+        let text = CompressedTextChunk::new("Description", &"spam ".repeat(100)).unwrap();
+        let chunk = text.to_chunk();
+
+        assert!(chunk.data().starts_with(b"Description\0\0"));
+        assert!(chunk.data().len() < 100);
+        assert_eq!(CompressedTextChunk::try_from(&chunk).unwrap(), text);
+    }
+
+    #[test]
+    fn test_international_text_chunk() {
+        for compressed in [false, true] {
+            let text = InternationalTextChunk::new(
+                "Title",
+                "ja",
+                "\u{984c}\u{540d}",
+                "\u{3053}\u{3093}\u{306b}\u{3061}\u{306f}",
+                compressed,
+            )
+            .unwrap();
+            let chunk = text.to_chunk();
+
+            assert!(chunk.data().starts_with(&[
+                b'T',
+                b'i',
+                b't',
+                b'l',
+                b'e',
+                0,
+                compressed as u8,
+                0
+            ]));
+            assert_eq!(InternationalTextChunk::try_from(&chunk).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_textual_chunk() {
+        let itxt = chunk("iTXt", b"Author\0\0\0\0\0Ferris");
+        let text = TextualChunk::try_from(&itxt).unwrap();
+
+        assert_eq!(text.keyword(), "Author");
+        assert_eq!(text.text(), "Ferris");
+        assert_eq!(&text.chunk_type(), itxt.chunk_type());
+        assert!(matches!(text, TextualChunk::International(_)));
+        assert!(text.to_chunk() == itxt);
+        assert_eq!(TextualChunk::try_from(itxt.as_chunk_ref(33)), Ok(text));
+
+        assert_eq!(TextualChunk::keyword_of(&itxt), Some("Author".to_string()));
+        assert_eq!(
+            TextualChunk::keyword_of(&chunk("zTXt", b"Title\0\0junk")),
+            Some("Title".to_string())
+        );
+        assert_eq!(TextualChunk::keyword_of(&chunk("tEXt", b"Title")), None);
+        assert_eq!(TextualChunk::keyword_of(&chunk("ruSt", b"Title\0")), None);
+        assert_eq!(
+            TextualChunk::try_from(chunk("ruSt", b"").as_chunk_ref(33)),
+            Err(PngError::UnexpectedChunkType {
+                offset: 33,
+                expected: *b"tEXt",
+                found: *b"ruSt",
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_keywords() {
+        for keyword in [
+            "",
+            " lead",
+            "trail ",
+            "two  spaces",
+            "tab\t",
+            "snow\u{2603}",
+        ] {
+            assert_eq!(
+                TextChunk::new(keyword, ""),
+                Err(PngError::InvalidKeyword {
+                    keyword: keyword.to_string()
+                })
+            );
+        }
+        assert!(TextChunk::new(&"k".repeat(79), "").is_ok());
+        assert!(TextChunk::new(&"k".repeat(80), "").is_err());
+        assert!(TextChunk::new("Caf\u{e9} au lait", "").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_text() {
+        assert_eq!(
+            TextChunk::new("Comment", "snow\u{2603}"),
+            Err(PngError::InvalidTextField {
+                field: "text",
+                reason: "can only contain Latin-1 characters",
+            })
+        );
+        assert!(TextChunk::new("Comment", "null\0").is_err());
+        assert!(InternationalTextChunk::new("Comment", "en_GB", "", "", false).is_err());
+        assert!(InternationalTextChunk::new("Comment", "en-GB", "", "snow\u{2603}", false).is_ok());
+    }
+
+    #[test]
+    fn test_malformed_chunks() {
+        let malformed = |chunk_type: &str, data: &[u8]| match TextualChunk::try_from(
+            chunk(chunk_type, data).as_chunk_ref(8),
+        ) {
+            Err(PngError::MalformedChunk {
+                offset: 8, reason, ..
+            }) => reason,
+            result => panic!("expected a malformed chunk, got {:?}", result),
+        };
+
+        assert_eq!(
+            malformed("tEXt", b"no separator"),
+            "it has no null after its keyword"
+        );
+        assert_eq!(
+            malformed("zTXt", b"Comment\0\x01abc"),
+            "it uses an unknown compression method"
+        );
+        assert_eq!(
+            malformed("zTXt", b"Comment\0\0not zlib"),
+            "its compressed data is corrupt"
+        );
+        assert_eq!(
+            TextualChunk::with_max_length(
+                CompressedTextChunk::new("Comment", "ab")
+                    .unwrap()
+                    .to_chunk()
+                    .as_chunk_ref(8),
+                1,
+            ),
+            Err(PngError::MalformedChunk {
+                offset: 8,
+                chunk_type: *b"zTXt",
+                reason: "its compressed data inflates to more than is allowed",
+            })
+        );
+        assert_eq!(
+            malformed("iTXt", b"Comment\0\0\0en"),
+            "it is missing a null separator"
+        );
+        assert_eq!(
+            malformed("iTXt", b"Comment\0\x02\0\0\0"),
+            "its compression flag is missing or invalid"
+        );
+        assert_eq!(
+            malformed("iTXt", b"Comment\0\0\0\0\0\xff"),
+            "its text is not valid UTF-8"
+        );
+    }
+}
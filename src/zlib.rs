@@ -0,0 +1,25 @@
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    // writing to a Vec can't fail
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+// Inflates zlib data, refusing to produce more than max_length bytes so that a
+// small, carefully made chunk can't use up all the memory there is.
+pub(crate) fn decompress_at_most(data: &[u8], max_length: usize) -> Result<Vec<u8>, &'static str> {
+    let mut inflated = Vec::new();
+    ZlibDecoder::new(data)
+        .take((max_length as u64).saturating_add(1))
+        .read_to_end(&mut inflated)
+        .map_err(|_| "its compressed data is corrupt")?;
+    if inflated.len() > max_length {
+        return Err("its compressed data inflates to more than is allowed");
+    }
+    Ok(inflated)
+}
@@ -0,0 +1,208 @@
+use pngme::payload;
+use pngme::{Chunk, ChunkType, ColourType, Ihdr, Png};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::str::FromStr;
+
+fn pngme(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pngme"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn message_chunks(path: &Path) -> usize {
+    let png = Png::try_from(std::fs::read(path).unwrap().as_slice()).unwrap();
+    png.chunks_by_type("ruSt").len()
+}
+
+fn chunk(chunk_type: &str, data: &[u8]) -> Chunk {
+    Chunk::new(ChunkType::from_str(chunk_type).unwrap(), data.to_vec())
+}
+
+// A 1x1 greyscale image, with `extra` between its header and its image data.
+fn png_with(extra: Vec<Chunk>) -> Png {
+    let ihdr = Ihdr::new(1, 1, 8, ColourType::Greyscale, false).unwrap();
+    let mut chunks = vec![ihdr.to_chunk()];
+    chunks.extend(extra);
+    chunks.push(chunk("IDAT", b""));
+    chunks.push(chunk("IEND", b""));
+    Png::from_chunks(chunks)
+}
+
+#[test]
+fn test_same_split_message_twice() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    std::fs::write(&path, png_with(Vec::new()).as_bytes()).unwrap();
+    let file = path.to_str().unwrap();
+    let message = "This is where your secret message will be!";
+
+    // each piece holds 14 bytes of it, so it takes 3
+    for _ in 0..2 {
+        stdout(pngme(&[
+            "encode",
+            file,
+            "ruSt",
+            message,
+            "--split-size",
+            "40",
+        ]));
+    }
+    assert_eq!(message_chunks(&path), 6);
+
+    assert_eq!(
+        stdout(pngme(&["decode", file, "ruSt", "--all"])),
+        format!("{message}\n{message}\n")
+    );
+    assert_eq!(
+        stdout(pngme(&["decode", file, "ruSt", "--index", "1"])),
+        format!("{message}\n")
+    );
+    assert!(!pngme(&["decode", file, "ruSt", "--index", "2"])
+        .status
+        .success());
+
+    stdout(pngme(&["remove", file, "ruSt"]));
+    assert_eq!(message_chunks(&path), 3);
+    assert_eq!(
+        stdout(pngme(&["decode", file, "ruSt"])),
+        format!("{message}\n")
+    );
+}
+
+#[test]
+fn test_decode_checks_crcs_up_to_the_message() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    let file = path.to_str().unwrap();
+    let png = png_with(vec![chunk("ruSt", b"hello")]);
+    // the CRC of an unrelated chunk, either before or after the message
+    let corrupt = |index: usize| {
+        let mut bytes = png.as_bytes();
+        let mut chunk = chunk("coRr", b"corrupt").as_bytes();
+        *chunk.last_mut().unwrap() ^= 1;
+        let at = Png::STANDARD_HEADER.len()
+            + png.chunks()[..index]
+                .iter()
+                .map(|c| c.as_bytes().len())
+                .sum::<usize>();
+        bytes.splice(at..at, chunk);
+        bytes
+    };
+
+    for args in [
+        &["decode", file, "ruSt"][..],
+        &["decode", file, "ruSt", "--mmap"],
+    ] {
+        std::fs::write(&path, corrupt(1)).unwrap();
+        let output = pngme(args);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("checksum for the coRr chunk"));
+
+        std::fs::write(&path, corrupt(2)).unwrap();
+        assert_eq!(stdout(pngme(args)), "hello\n");
+    }
+}
+
+#[test]
+fn test_remove_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    let file = path.to_str().unwrap();
+    let bytes = png_with(vec![chunk("ruSt", b"hello")]).as_bytes();
+    std::fs::write(&path, &bytes).unwrap();
+    // a rewritten file would be a new one renamed into place
+    #[cfg(unix)]
+    let inode = || std::os::unix::fs::MetadataExt::ino(&std::fs::metadata(&path).unwrap());
+    #[cfg(unix)]
+    let before = inode();
+
+    for args in [
+        &["remove", file, "zzZz"][..],
+        &["remove", file, "zzZz", "--all"],
+        &["remove", file, "ruSt", "--index", "1"],
+    ] {
+        let output = pngme(args);
+        assert!(!output.status.success());
+        assert!(!output.stderr.is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+        #[cfg(unix)]
+        assert_eq!(inode(), before);
+    }
+}
+
+#[test]
+fn test_message_that_looks_wrapped() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    let file = path.to_str().unwrap();
+    let data = dir.path().join("data");
+    std::fs::write(&path, png_with(Vec::new()).as_bytes()).unwrap();
+    // the header of a compressed message, with nothing compressed after it
+    let message = b"\x89pme\x01\x01junk";
+    std::fs::write(&data, message).unwrap();
+    let data = data.to_str().unwrap();
+
+    stdout(pngme(&["encode", file, "ruSt", "--data-file", data]));
+    stdout(pngme(&[
+        "encode",
+        file,
+        "ruSt",
+        "--data-file",
+        data,
+        "--compress",
+    ]));
+
+    let hex: String = message.iter().map(|b| format!("{b:02x}")).collect();
+    assert_eq!(
+        stdout(pngme(&["decode", file, "ruSt", "--all", "--hex"])),
+        format!("{hex}\n{hex}\n")
+    );
+    let mut stored = b"\x89pme\x01\x05".to_vec();
+    stored.extend_from_slice(message);
+    assert_eq!(pngme(&["decode", file, "ruSt", "--raw"]).stdout, stored);
+}
+
+#[test]
+fn test_decode_max_length() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    let file = path.to_str().unwrap();
+    std::fs::write(&path, png_with(Vec::new()).as_bytes()).unwrap();
+    let message = "a".repeat(1000);
+
+    stdout(pngme(&["encode", file, "ruSt", &message, "--compress"]));
+    assert!(!pngme(&["decode", file, "ruSt", "--max-length", "999"])
+        .status
+        .success());
+    assert_eq!(
+        stdout(pngme(&["decode", file, "ruSt", "--max-length", "1000"])),
+        format!("{message}\n")
+    );
+}
+
+#[test]
+fn test_split_piece_twice() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("image.png");
+    let file = path.to_str().unwrap();
+    let pieces = payload::split(b"This is where your secret message will be!", 40).unwrap();
+    assert_eq!(pieces.len(), 3);
+    let chunks = [0, 0, 1, 2].map(|i| chunk("ruSt", &pieces[i]));
+    std::fs::write(&path, png_with(chunks.to_vec()).as_bytes()).unwrap();
+
+    let output = pngme(&["decode", file, "ruSt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("piece 0 (counting from 0) is there more than once"));
+}